@@ -0,0 +1,112 @@
+//! Compares prune time and peak memory across pool generation counts.
+//!
+//! Run with `cargo bench --bench prune`. One generation is the original
+//! scheme, which copies every surviving state at each prune. Peak memory is
+//! read from `/proc`, so it is only reported on Linux.
+
+use nessie::{prune::PolicySpec, Chain};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+const LINES: usize = 20_000;
+const WORDS_PER_LINE: usize = 200;
+const VOCABULARY: usize = 50_000;
+const PRUNE_SIZE: usize = 64 << 20;
+
+// Peak resident set size in bytes since the last `reset_peak_rss`.
+fn peak_rss() -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kib| kib.parse::<usize>().ok())
+        .map_or(0, |kib| kib * 1024)
+}
+
+fn reset_peak_rss() {
+    let _ = fs::write("/proc/self/clear_refs", "5");
+}
+
+// Lines of words drawn from a skewed distribution, so that a few states
+// are common and survive prunes while most are rare.
+fn corpus() -> (Vec<String>, Vec<Vec<u32>>) {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let vocabulary: Vec<String> = (0..VOCABULARY)
+        .map(|_| {
+            let len = rng.gen_range(3..12);
+            (0..len).map(|_| rng.gen_range('a'..='z')).collect()
+        })
+        .collect();
+
+    let lines = (0..LINES)
+        .map(|_| {
+            (0..WORDS_PER_LINE)
+                .map(|_| (rng.gen::<f64>().powi(4) * VOCABULARY as f64) as u32)
+                .collect()
+        })
+        .collect();
+
+    (vocabulary, lines)
+}
+
+fn train((vocabulary, lines): &(Vec<String>, Vec<Vec<u32>>), generations: usize) {
+    let policy = PolicySpec::topics(4).build();
+    let mut chain = Chain::new(64, PRUNE_SIZE, policy);
+    chain.set_generations(generations);
+
+    reset_peak_rss();
+    let baseline = peak_rss();
+
+    let start = Instant::now();
+    let mut prune_time = Duration::default();
+
+    for line in lines {
+        let words: Vec<&str> = line
+            .iter()
+            .map(|&i| vocabulary[i as usize].as_str())
+            .collect();
+
+        let num_prunes = chain.num_prunes();
+        let update_start = Instant::now();
+
+        chain.update(&words).unwrap();
+
+        if chain.num_prunes() > num_prunes {
+            prune_time += update_start.elapsed();
+        }
+    }
+
+    let total_time = start.elapsed();
+    let peak = peak_rss().saturating_sub(baseline);
+
+    println!(
+        "{:>11} {:>7} {:>10.3}s {:>10.3}ms {:>10.3}s {:>9.1} MiB {:>9}",
+        generations,
+        chain.num_prunes(),
+        prune_time.as_secs_f64(),
+        prune_time.as_secs_f64() * 1000.0 / chain.num_prunes().max(1) as f64,
+        total_time.as_secs_f64(),
+        peak as f64 / (1 << 20) as f64,
+        chain.num_entries(),
+    );
+}
+
+fn main() {
+    let corpus = corpus();
+
+    println!(
+        "{:>11} {:>7} {:>11} {:>12} {:>11} {:>13} {:>9}",
+        "generations", "prunes", "prune time", "per prune", "total", "peak", "entries"
+    );
+
+    for generations in 1..=4 {
+        train(&corpus, generations);
+    }
+}
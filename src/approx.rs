@@ -0,0 +1,600 @@
+use crate::chain::{
+    for_each_transition, Bigram, ObservationCounts, SentenceOptions, TopicMap, Unigram,
+};
+use crate::error::Result;
+use crate::model::{self, Metadata};
+use crate::tokenizer::StopWords;
+
+use ahash::AHasher;
+use hashbrown::hash_map::{HashMap, RawEntryMut};
+
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    hash::{BuildHasherDefault, Hash, Hasher},
+    io::Write,
+};
+
+/// A key with its Space-Saving count, and an upper bound on how much of that
+/// count may belong to keys it replaced.
+pub struct Counted<K, V = ()> {
+    pub key: K,
+    pub count: u32,
+    pub error: u32,
+    pub value: V,
+}
+
+// Position of a key in `SpaceSaving::entries`, stored in the index under
+// the key's hash so that the key itself is only held once.
+struct Slot(usize);
+
+/// Counts at most `capacity` distinct keys with the Space-Saving algorithm:
+/// a new key replaces the least counted one and inherits its count as error.
+/// Any key seen more than `total / capacity` times is kept, and no count is
+/// off by more than that.
+///
+/// Keys are kept in a binary heap on their count, with an index from each
+/// key's hash to its place in the heap, so that counting one occurrence
+/// takes logarithmic time in the capacity.
+pub struct SpaceSaving<K, V = ()> {
+    capacity: usize,
+    total: u32,
+
+    // A min-heap on count, so that the least counted key is first.
+    entries: Vec<Counted<K, V>>,
+    hashes: Vec<u64>,
+    index: HashMap<Slot, (), BuildHasherDefault<AHasher>>,
+}
+
+impl<K, V> SpaceSaving<K, V> {
+    /// Panics if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Space-Saving capacity must be at least 1");
+
+        SpaceSaving {
+            capacity,
+            total: 0,
+
+            entries: Vec::new(),
+            hashes: Vec::new(),
+            index: HashMap::default(),
+        }
+    }
+
+    /// Counts one occurrence of the key matching `eq`, building it with
+    /// `key` if it is not tracked. `query` must hash the same way for every
+    /// occurrence of a key, and is usually the key in borrowed form.
+    pub fn add<Q, E, F>(&mut self, query: &Q, eq: E, key: F) -> &mut V
+    where
+        Q: Hash + ?Sized,
+        E: Fn(&K) -> bool,
+        F: FnOnce() -> K,
+        V: Default,
+    {
+        self.add_with(query, eq, key, V::default)
+    }
+
+    /// As `add`, building the value of a newly tracked key with `value`.
+    /// The value is replaced along with its key.
+    pub fn add_with<Q, E, F, G>(&mut self, query: &Q, eq: E, key: F, value: G) -> &mut V
+    where
+        Q: Hash + ?Sized,
+        E: Fn(&K) -> bool,
+        F: FnOnce() -> K,
+        G: FnOnce() -> V,
+    {
+        self.total = self.total.saturating_add(1);
+
+        let mut hasher = AHasher::default();
+        query.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let entries = &self.entries;
+        let found = self
+            .index
+            .raw_entry()
+            .from_hash(hash, |slot| eq(&entries[slot.0].key))
+            .map(|(slot, _)| slot.0);
+
+        let i = match found {
+            Some(i) => {
+                self.entries[i].count = self.entries[i].count.saturating_add(1);
+                self.sift_down(i)
+            }
+            None if self.entries.len() < self.capacity => {
+                let i = self.entries.len();
+
+                self.entries.push(Counted {
+                    key: key(),
+                    count: 1,
+                    error: 0,
+                    value: value(),
+                });
+                self.hashes.push(hash);
+                self.insert_slot(i);
+
+                self.sift_up(i)
+            }
+            None => {
+                self.remove_slot(0);
+
+                let min = self.entries[0].count;
+                self.entries[0] = Counted {
+                    key: key(),
+                    count: min.saturating_add(1),
+                    error: min,
+                    value: value(),
+                };
+                self.hashes[0] = hash;
+                self.insert_slot(0);
+
+                self.sift_down(0)
+            }
+        };
+
+        &mut self.entries[i].value
+    }
+
+    /// Number of occurrences counted, tracked or not.
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// The most any count can be overestimated by.
+    pub fn error_bound(&self) -> u32 {
+        self.total / self.capacity as u32
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Tracked keys, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Counted<K, V>> {
+        self.entries.iter()
+    }
+
+    fn insert_slot(&mut self, i: usize) {
+        let hashes = &self.hashes;
+        if let RawEntryMut::Vacant(entry) = self
+            .index
+            .raw_entry_mut()
+            .from_hash(hashes[i], |slot| slot.0 == i)
+        {
+            entry.insert_with_hasher(hashes[i], Slot(i), (), |slot| hashes[slot.0]);
+        }
+    }
+
+    fn remove_slot(&mut self, i: usize) {
+        if let RawEntryMut::Occupied(entry) = self
+            .index
+            .raw_entry_mut()
+            .from_hash(self.hashes[i], |slot| slot.0 == i)
+        {
+            entry.remove();
+        }
+    }
+
+    fn move_slot(&mut self, hash: u64, from: usize, to: usize) {
+        if let RawEntryMut::Occupied(mut entry) = self
+            .index
+            .raw_entry_mut()
+            .from_hash(hash, |slot| slot.0 == from)
+        {
+            entry.key_mut().0 = to;
+        }
+    }
+
+    fn swap(&mut self, i: usize, j: usize) {
+        self.entries.swap(i, j);
+        self.hashes.swap(i, j);
+
+        self.move_slot(self.hashes[i], j, i);
+        self.move_slot(self.hashes[j], i, j);
+    }
+
+    // Moves the entry at `i` up past parents with higher counts, returning
+    // where it ends up.
+    fn sift_up(&mut self, mut i: usize) -> usize {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.entries[parent].count <= self.entries[i].count {
+                break;
+            }
+
+            self.swap(i, parent);
+            i = parent;
+        }
+
+        i
+    }
+
+    // Moves the entry at `i` down past children with lower counts, returning
+    // where it ends up.
+    fn sift_down(&mut self, mut i: usize) -> usize {
+        loop {
+            let mut least = i;
+            for child in [2 * i + 1, 2 * i + 2] {
+                if child < self.entries.len()
+                    && self.entries[child].count < self.entries[least].count
+                {
+                    least = child;
+                }
+            }
+
+            if least == i {
+                return i;
+            }
+
+            self.swap(i, least);
+            i = least;
+        }
+    }
+}
+
+/// Capacity giving an error bound of `epsilon` times the number of
+/// occurrences.
+pub fn capacity_for_error(epsilon: f64) -> usize {
+    (1.0 / epsilon).ceil() as usize
+}
+
+type Successors = SpaceSaving<Option<Unigram>>;
+type Topics = SpaceSaving<Bigram, Successors>;
+type States = SpaceSaving<Bigram, Topics>;
+
+/// `(successor, count, error)`, most frequent first.
+pub type ApproxSuccessors = Vec<(Option<Unigram>, u32, u32)>;
+
+/// `(topic, count, error, successors)`, most frequent first.
+pub type ApproxTopics = Vec<(Bigram, u32, u32, ApproxSuccessors)>;
+
+pub type ApproxChainMap = HashMap<Bigram, ApproxTopics>;
+
+/// A chain that bounds its states, the topics of each state and the
+/// successors of each topic with Space-Saving counters instead of keeping
+/// every observation. When a state or topic is replaced, so is everything
+/// under it. Memory is fixed by the three capacities, so it never needs
+/// pruning, at the cost of approximate counts and no sequence numbers.
+pub struct ApproxChain {
+    half_para_len: usize,
+    topic_capacity: usize,
+    successor_capacity: usize,
+    stop_words: StopWords,
+    sentences: SentenceOptions,
+    tokenizer: Option<String>,
+    rules_hash: Option<String>,
+
+    states: States,
+}
+
+impl ApproxChain {
+    /// Panics if any capacity is 0.
+    pub fn new(
+        half_para_len: usize,
+        state_capacity: usize,
+        topic_capacity: usize,
+        successor_capacity: usize,
+    ) -> Self {
+        assert!(topic_capacity > 0 && successor_capacity > 0);
+
+        ApproxChain {
+            half_para_len,
+            topic_capacity,
+            successor_capacity,
+            stop_words: StopWords::default(),
+            sentences: SentenceOptions::default(),
+            tokenizer: None,
+            rules_hash: None,
+
+            states: States::new(state_capacity),
+        }
+    }
+
+    /// Leaves `stop_words` out of topic selection, as in `Chain`.
+    pub fn set_stop_words(&mut self, stop_words: StopWords) {
+        self.stop_words = stop_words;
+    }
+
+    /// Sets sentence options, as in `Chain`.
+    pub fn set_sentence_options(&mut self, sentences: SentenceOptions) {
+        self.sentences = sentences;
+    }
+
+    /// Records the tokenizer's name in the model metadata, as in `Chain`.
+    pub fn set_tokenizer_name(&mut self, tokenizer: Option<String>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Records the normalization rules' hash in the model metadata, as in
+    /// `Chain`.
+    pub fn set_rules_hash(&mut self, rules_hash: Option<String>) {
+        self.rules_hash = rules_hash;
+    }
+
+    pub fn update(&mut self, words: &[&str]) {
+        self.update_sentences(words, &[])
+    }
+
+    /// Like `update`, for a line whose sentences end before each index in
+    /// `sentence_ends`.
+    pub fn update_sentences(&mut self, words: &[&str], sentence_ends: &[usize]) {
+        let ApproxChain {
+            half_para_len,
+            topic_capacity,
+            successor_capacity,
+            stop_words,
+            sentences,
+            states,
+            ..
+        } = self;
+
+        for_each_transition(
+            words,
+            sentence_ends,
+            *half_para_len,
+            *sentences,
+            stop_words.words(),
+            |state, topic, _, next| {
+                states
+                    .add_with(
+                        &state,
+                        |k| k.0 == state.0 && k.1 == state.1,
+                        || (state.0.into(), state.1.into()),
+                        || Topics::new(*topic_capacity),
+                    )
+                    .add_with(
+                        &topic,
+                        |k| k.0 == topic.0 && k.1 == topic.1,
+                        || (topic.0.into(), topic.1.into()),
+                        || Successors::new(*successor_capacity),
+                    )
+                    .add(&next, |k| k.as_deref() == next, || next.map(|w| w.into()));
+            },
+        );
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Every state with its topics and successors, and their counts and
+    /// error bounds.
+    pub fn extract_map(&self) -> ApproxChainMap {
+        let mut new_chain = ApproxChainMap::with_capacity(self.num_entries());
+        for state in self.states.iter() {
+            let mut new_topics: ApproxTopics = state
+                .value
+                .iter()
+                .map(|topic| {
+                    let mut successors: ApproxSuccessors = topic
+                        .value
+                        .iter()
+                        .map(|s| (s.key.clone(), s.count, s.error))
+                        .collect();
+
+                    successors.sort_unstable_by_key(|s| Reverse(s.1));
+                    (topic.key.clone(), topic.count, topic.error, successors)
+                })
+                .collect();
+
+            new_topics.sort_unstable_by_key(|t| Reverse(t.1));
+            new_chain.insert(state.key.clone(), new_topics);
+        }
+
+        new_chain
+    }
+
+    /// Writes a model file that loads with `Model::load`. The chain lists
+    /// each successor once, with sequence number 0, and each topic's count
+    /// is recorded in the metadata as for a sampled list. Every count and
+    /// its error bound is written once in the file's `approx` section, so
+    /// the file is bounded by the capacities however high the counts.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let approx = self.extract_map();
+        let observation_counts = RefCell::new(HashMap::new());
+
+        let entries = approx.iter().map(|(state, topics)| {
+            let mut topic_map = TopicMap::with_capacity(topics.len());
+            let mut counts = ObservationCounts::new();
+
+            for (topic, count, _, successors) in topics {
+                let observations: Vec<_> = successors.iter().map(|s| (0, s.0.clone())).collect();
+
+                if *count as usize != observations.len() {
+                    counts.insert(topic.clone(), *count as usize);
+                }
+
+                topic_map.insert(topic.clone(), observations);
+            }
+
+            if !counts.is_empty() {
+                observation_counts
+                    .borrow_mut()
+                    .insert(state.clone(), counts);
+            }
+
+            Ok((state, topic_map))
+        });
+
+        let metadata = || Metadata {
+            observation_counts: observation_counts.take(),
+            tokenizer: self.tokenizer.clone(),
+            rules_hash: self.rules_hash.clone(),
+            stop_words: self.stop_words.sorted(),
+            stop_word_mode: Some(self.stop_words.mode()),
+            approximate: true,
+            ..Metadata::default()
+        };
+
+        model::write_model_streamed_with(writer, entries, metadata, Some(&approx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::TopicWindow;
+    use crate::model::Model;
+    use crate::tokenizer::{StopWordMode, WhitespaceTokenizer};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn add(counter: &mut SpaceSaving<&'static str>, key: &'static str) {
+        counter.add(key, |k| *k == key, || key);
+    }
+
+    #[test]
+    fn test_space_saving() {
+        let mut counter = SpaceSaving::new(2);
+
+        for key in ["a", "a", "a", "b", "c", "a", "c"] {
+            add(&mut counter, key);
+        }
+
+        let mut counts: Vec<_> = counter.iter().map(|e| (e.key, e.count, e.error)).collect();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![("a", 4, 0), ("c", 3, 1)]);
+
+        assert_eq!(counter.total(), 7);
+        assert_eq!(counter.error_bound(), 3);
+    }
+
+    #[test]
+    fn test_space_saving_bounds() {
+        let keys: Vec<String> = (0..1000).map(|i| (i * i % 97).to_string()).collect();
+        let mut counter = SpaceSaving::<String>::new(16);
+        let mut exact = HashMap::<&str, u32>::new();
+
+        for key in &keys {
+            counter.add(key.as_str(), |k| k == key, || key.clone());
+            *exact.entry(key.as_str()).or_insert(0) += 1;
+        }
+
+        assert_eq!(counter.len(), 16);
+        for entry in counter.iter() {
+            let count = exact[entry.key.as_str()];
+            assert!(entry.count >= count && entry.count - entry.error <= count);
+            assert!(entry.count - count <= counter.error_bound());
+        }
+
+        // Every key seen more than total / capacity times is kept.
+        for (key, &count) in exact.iter() {
+            if count > counter.error_bound() {
+                assert!(counter.iter().any(|e| e.key == *key));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_space_saving_zero_capacity() {
+        SpaceSaving::<String>::new(0);
+    }
+
+    #[test]
+    fn test_capacity_for_error() {
+        assert_eq!(capacity_for_error(0.1), 10);
+        assert_eq!(capacity_for_error(0.03), 34);
+    }
+
+    #[test]
+    fn test_sentence_boundaries() {
+        let words = [
+            "red", "cats", "nap", "red", "cats", "purr", "blue", "dogs", "bark", "blue", "dogs",
+        ];
+        let state = |a: &str, b: &str| (a.to_string(), b.to_string());
+
+        let mut chain = ApproxChain::new(0, 16, 4, 4);
+        chain.set_sentence_options(SentenceOptions {
+            boundaries: true,
+            window: TopicWindow::Sentences,
+        });
+        chain.update_sentences(&words, &[6]);
+
+        let map = chain.extract_map();
+        assert!(!map.contains_key(&state("purr", "blue")));
+        assert_eq!(map[&state("red", "cats")][0].3.len(), 2);
+        assert_eq!(map[&state("cats", "purr")][0].3[0].0, None);
+
+        // Each topic only comes from its own sentence.
+        let (first, second) = &map[&state("blue", "dogs")][0].0;
+        assert!(words[6..].contains(&first.as_str()) && words[6..].contains(&second.as_str()));
+    }
+
+    #[test]
+    fn test_write_model() {
+        let words = [
+            "red", "cats", "nap", "red", "cats", "purr", "red", "cats", "nap",
+        ];
+
+        let mut chain = ApproxChain::new(0, 16, 4, 4);
+        chain.set_sentence_options(SentenceOptions {
+            boundaries: false,
+            window: TopicWindow::Document,
+        });
+        chain.set_stop_words(StopWords::new(
+            "purr",
+            &WhitespaceTokenizer,
+            StopWordMode::Topics,
+        ));
+        chain.update(&words);
+
+        let mut file = Vec::new();
+        chain.write(&mut file).unwrap();
+
+        let model = Model::from_reader(&file[..]).unwrap();
+        assert!(model.metadata().approximate);
+        assert_eq!(model.metadata().stop_words, ["purr"]);
+        assert_eq!(model.metadata().stop_word_mode, Some(StopWordMode::Topics));
+        assert_eq!(model.num_entries(), chain.num_entries());
+
+        // The state was seen three times, with two distinct successors.
+        let topics: Vec<_> = model.topics(("red", "cats")).collect();
+        assert_eq!(topics.iter().map(|(_, count)| count).sum::<usize>(), 3);
+
+        for (topic, _) in topics {
+            let successors = model.successors(("red", "cats"), topic).unwrap();
+            assert_eq!(successors.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_write_error_bounds() {
+        let words = [
+            "red", "cats", "nap", "red", "cats", "purr", "red", "cats", "nap",
+        ];
+
+        // One successor slot, so each new successor replaces the last.
+        let mut chain = ApproxChain::new(0, 16, 4, 1);
+        chain.set_sentence_options(SentenceOptions {
+            boundaries: false,
+            window: TopicWindow::Document,
+        });
+        chain.update(&words);
+
+        let mut file = Vec::new();
+        chain.write(&mut file).unwrap();
+        let model = Model::from_reader(&file[..]).unwrap();
+
+        let topics = model.approx_topics(("red", "cats")).unwrap();
+        assert_eq!(topics, &chain.extract_map()[&("red".into(), "cats".into())]);
+        assert_eq!(topics[0].3, vec![(Some("nap".into()), 3, 2)]);
+
+        // Rewriting the model keeps them.
+        let mut rewritten = Vec::new();
+        model.write(&mut rewritten).unwrap();
+        let model = Model::from_reader(&rewritten[..]).unwrap();
+        assert_eq!(model.approx_topics(("red", "cats")).unwrap()[0].3[0].2, 2);
+
+        let topic = &topics[0].0;
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            model.generate(("red", "cats"), (&topic.0, &topic.1), 3, &mut rng),
+            ["red", "cats", "nap"]
+        );
+    }
+}
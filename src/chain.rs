@@ -1,11 +1,34 @@
 use crate::clone_in::CloneIn;
 use crate::counter::Counter;
+use crate::error::{Error, Result};
+use crate::memory::{self, MemoryReport, Pool};
+use crate::model::{self, Metadata};
+use crate::prune::{
+    self, AutoThreshold, Both, FinalPrune, Floor, Histogram, Metric, PrunePolicy, PruneStats,
+    StateStats, Threshold, TopicStats,
+};
+use crate::query::Successors;
+use crate::sample;
+use crate::sketch::Prefilter;
+use crate::spill::Runs;
+use crate::tokenizer::StopWords;
 use crate::unigram;
 
-use bumpalo::Bump;
-use hashbrown::HashMap;
+use hashbrown::{
+    hash_map::{HashMap, RawEntryMut},
+    HashSet,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use std::{cell::UnsafeCell, cmp::min, hash::Hash, mem};
+use std::{
+    cell::{RefCell, UnsafeCell},
+    cmp::min,
+    hash::{BuildHasher, Hash, Hasher},
+    io::Write,
+    mem::{self, size_of, ManuallyDrop},
+    path::Path,
+    sync::Arc,
+};
 
 pub type Unigram = String;
 pub type Bigram = (String, String);
@@ -13,34 +36,91 @@ pub type Bigram = (String, String);
 pub type TopicMap = HashMap<Bigram, Vec<(i32, Option<Unigram>)>>;
 pub type ChainMap = HashMap<Bigram, TopicMap>;
 
-type BUnigram<'a> = unigram::Unigram<&'a Bump>;
+/// True number of observations of each topic whose list was sampled down.
+/// Topics not listed were kept in full.
+pub type ObservationCounts = HashMap<Bigram, usize>;
+
+pub(crate) type BUnigram<'a> = unigram::Unigram<&'a Pool>;
 type BBigram<'a> = (BUnigram<'a>, BUnigram<'a>);
 
-type BHashMap<'a, K, V> = HashMap<K, V, ahash::RandomState, &'a Bump>;
-type BVec<'a, T> = Vec<T, &'a Bump>;
+type BHashMap<'a, K, V> = HashMap<K, V, ahash::RandomState, &'a Pool>;
+type BVec<'a, T> = Vec<T, &'a Pool>;
 
-type BTopicMap<'a> = BHashMap<'a, BBigram<'a>, BVec<'a, (i32, Option<BUnigram<'a>>)>>;
-type BChainMap<'a> = BHashMap<'a, BBigram<'a>, BTopicMap<'a>>;
+struct BObservations<'a> {
+    seen: usize,
+    list: BVec<'a, (i32, Option<BUnigram<'a>>)>,
+}
 
-pub struct Chain<'a> {
+impl<'a> BObservations<'a> {
+    fn with_capacity_in(capacity: usize, seen: usize, pool: &'a Pool) -> Self {
+        BObservations {
+            seen,
+            list: BVec::with_capacity_in(capacity, pool),
+        }
+    }
+}
+type BTopicMap<'a> = BHashMap<'a, BBigram<'a>, BObservations<'a>>;
+
+// Everything a state owns is allocated in its generation's pool.
+struct BState<'a> {
+    generation: usize,
+    topics: BTopicMap<'a>,
+}
+
+type BChainMap<'a> = BHashMap<'a, BBigram<'a>, BState<'a>>;
+
+// The bump-allocated maps below borrow from `pools`, which `Chain` owns, so the
+// `'static` is a lie that must never escape through the public API. `chain` is
+// declared before `pools` so that it is dropped first. The table itself lives
+// in the oldest generation's pool.
+type PoolRef = &'static Pool;
+
+pub struct Chain {
     half_para_len: usize,
     prune_size: usize,
-    prune_threshold: usize,
+    prune_policy: Arc<dyn PrunePolicy>,
+    final_policy: Option<Arc<dyn PrunePolicy>>,
+    final_min_entries: usize,
+    skip_final_prune: bool,
+    auto_threshold: Option<AutoThreshold>,
+    runs: Option<Runs>,
+    prefilter: Option<Prefilter>,
+    stop_words: StopWords,
+    sentences: SentenceOptions,
+    tokenizer: Option<String>,
+    rules_hash: Option<String>,
+    prune_history: Vec<PruneStats>,
+
+    max_memory: Option<usize>,
+    max_update_bytes: usize,
+
+    sample_size: Option<usize>,
+    rng: StdRng,
 
     hasher: ahash::RandomState,
-    chain: BChainMap<'a>,
+    chain: BChainMap<'static>,
 
-    pools: Vec<UnsafeCell<Bump>>,
-    active_pool: usize,
+    pools: Vec<UnsafeCell<Pool>>,
+    // Pool of each generation, youngest first, and the one left over for
+    // compacting the oldest into.
+    generation_pools: Vec<usize>,
+    spare_pool: usize,
 }
 
-impl<'a> Chain<'a> {
-    pub fn new(half_para_len: usize, prune_size: usize, prune_threshold: usize) -> Self {
-        let bump_capacity = (prune_size as f64 * 1.1) as usize;
+// The pools are only reached through `chain` and `pools`, which move with
+// the `Chain` as a whole, so it can be sent to another thread even though
+// `&Pool` is not `Send`.
+unsafe impl Send for Chain {}
 
+impl Chain {
+    pub fn new(
+        half_para_len: usize,
+        prune_size: usize,
+        prune_policy: Box<dyn PrunePolicy>,
+    ) -> Self {
         let pools = vec![
-            UnsafeCell::new(Bump::with_capacity(bump_capacity)),
-            UnsafeCell::new(Bump::with_capacity(bump_capacity)),
+            UnsafeCell::new(Pool::with_capacity(pool_capacity(prune_size))),
+            UnsafeCell::new(Pool::with_capacity(pool_capacity(prune_size))),
         ];
 
         let hasher = ahash::RandomState::new();
@@ -48,7 +128,24 @@ impl<'a> Chain<'a> {
         Chain {
             half_para_len,
             prune_size,
-            prune_threshold,
+            prune_policy: prune_policy.into(),
+            final_policy: None,
+            final_min_entries: 0,
+            skip_final_prune: false,
+            auto_threshold: None,
+            runs: None,
+            prefilter: None,
+            stop_words: StopWords::default(),
+            sentences: SentenceOptions::default(),
+            tokenizer: None,
+            rules_hash: None,
+            prune_history: Vec::new(),
+
+            max_memory: None,
+            max_update_bytes: 0,
+
+            sample_size: None,
+            rng: StdRng::from_entropy(),
 
             hasher: hasher.clone(),
             chain: BChainMap::with_capacity_and_hasher_in(prune_size / 1000, hasher, unsafe {
@@ -56,126 +153,757 @@ impl<'a> Chain<'a> {
             }),
 
             pools,
-            active_pool: 0,
+            generation_pools: vec![0],
+            spare_pool: 1,
         }
     }
 
-    fn active_pool(&self) -> &'a Bump {
-        unsafe { &*self.pools[self.active_pool].get() }
+    fn pool(&self, id: usize) -> PoolRef {
+        unsafe { &*self.pools[id].get() }
+    }
+
+    fn generation_pool(&self, generation: usize) -> PoolRef {
+        self.pool(self.generation_pools[generation])
     }
 
-    fn advance_pool(&mut self) -> &'a Bump {
-        self.active_pool = (self.active_pool + 1) % self.pools.len();
-        self.active_pool()
+    fn oldest_generation(&self) -> usize {
+        self.generation_pools.len() - 1
     }
 
-    pub fn update(&mut self, words: &[&str]) {
-        if words.len() < self.half_para_len {
-            return;
+    /// Splits the pools into `generations`, youngest first. New states go in
+    /// the youngest, and a prune promotes its survivors to the next one
+    /// instead of copying every state. Only the oldest is compacted, once
+    /// the younger ones no longer free enough room. With one generation,
+    /// every prune copies everything. Must be called before training.
+    pub fn set_generations(&mut self, generations: usize) {
+        assert!(
+            self.chain.is_empty(),
+            "generations must be set before training"
+        );
+
+        let generations = generations.max(1);
+        while self.pools.len() < generations + 1 {
+            let pool = Pool::with_capacity(pool_capacity(self.prune_size));
+            self.pools.push(UnsafeCell::new(pool));
         }
 
-        let pool = self.active_pool();
+        self.generation_pools = (0..generations).collect();
+        self.spare_pool = generations;
 
-        let mut seq_num = 0;
-        let mut previous_topic_bigram = (
-            BUnigram::from_slice_in("", pool),
-            BUnigram::from_slice_in("", pool),
+        let new_chain = self.new_hash_map(
+            self.prune_size / 1000,
+            self.generation_pool(generations - 1),
         );
 
-        let mut counter = Counter::new();
+        unsafe { self.replace_chain(new_chain) }
+        self.pools.truncate(generations + 1);
+    }
 
-        for i in 0..(words.len() - 1) {
-            let start = i.saturating_sub(self.half_para_len);
-            let end = min(i.saturating_add(self.half_para_len), words.len());
+    pub fn generations(&self) -> usize {
+        self.generation_pools.len()
+    }
 
-            let para = &words[start..end];
+    pub fn update(&mut self, words: &[&str]) -> Result<()> {
+        self.update_sentences(words, &[])
+    }
 
-            if i == 0 {
-                for &word in para.iter().filter(|w| w.len() > 2) {
-                    counter.add(word);
-                }
-            } else {
-                if start > 0 {
-                    let word = words[start];
-                    if word.len() > 2 {
-                        counter.remove(word);
+    /// Like `update`, for a line whose sentences end before each index in
+    /// `sentence_ends`. The ends only matter with sentence options set.
+    pub fn update_sentences(&mut self, words: &[&str], sentence_ends: &[usize]) -> Result<()> {
+        let bytes_before = self.allocated_bytes();
+        let nursery = self.generation_pool(0);
+        let Chain {
+            half_para_len,
+            prefilter,
+            stop_words,
+            sentences,
+            sample_size,
+            rng,
+            hasher,
+            chain,
+            pools,
+            generation_pools,
+            ..
+        } = self;
+
+        for_each_transition(
+            words,
+            sentence_ends,
+            *half_para_len,
+            *sentences,
+            stop_words.words(),
+            |state, topic, seq_num, next| {
+                if let Some(prefilter) = prefilter.as_mut() {
+                    if !prefilter.admits(state) {
+                        prefilter.skip(new_entry_bytes(state, next));
+                        return;
                     }
                 }
 
-                if end < words.len() || i + self.half_para_len == words.len() {
-                    let word = words[end - 1];
-                    if word.len() > 2 {
-                        counter.add(word);
+                let state = get_or_insert_with(
+                    chain,
+                    hasher,
+                    state,
+                    || BState {
+                        generation: 0,
+                        topics: BHashMap::with_hasher_in(hasher.clone(), nursery),
+                    },
+                    nursery,
+                );
+
+                let pool: PoolRef = unsafe { &*pools[generation_pools[state.generation]].get() };
+                let observations = get_or_insert_with(
+                    &mut state.topics,
+                    hasher,
+                    topic,
+                    || BObservations::with_capacity_in(0, 0, pool),
+                    pool,
+                );
+
+                let next_unigram = || next.map(|w| BUnigram::from_slice_in(w, pool));
+                match *sample_size {
+                    Some(capacity) if observations.list.len() >= capacity => {
+                        if let Some(slot) = sample::reservoir_slot(observations.seen, capacity, rng)
+                        {
+                            observations.list[slot] = (seq_num, next_unigram());
+                        }
                     }
+                    _ => observations.list.push((seq_num, next_unigram())),
+                }
+
+                observations.seen += 1;
+            },
+        );
+
+        self.max_update_bytes = self
+            .max_update_bytes
+            .max(self.allocated_bytes().saturating_sub(bytes_before));
+
+        if self.used_bytes() > self.prune_size || self.allocated_bytes() > self.pool_limit() {
+            match self.runs {
+                Some(_) => self.spill()?,
+                None => {
+                    self.prune()?;
                 }
             }
+        }
 
-            if counter.total_count() < 3 || counter.num_items() < 2 {
-                break;
+        match self.max_memory {
+            Some(max_memory) if self.allocated_bytes() > self.pool_limit() => {
+                Err(Error::MemoryCap(max_memory))
             }
+            _ => Ok(()),
+        }
+    }
+
+    fn new_hash_map<K: Hash + Eq, V>(&self, size: usize, pool: PoolRef) -> BHashMap<'static, K, V> {
+        BHashMap::with_capacity_and_hasher_in(size, self.hasher.clone(), pool)
+    }
 
-            let topic_bigram = (
-                BUnigram::from_slice_in(counter.most_frequent(1).unwrap().0, pool),
-                BUnigram::from_slice_in(counter.most_frequent(2).unwrap().0, pool),
+    pub fn set_prune_policy(&mut self, prune_policy: Box<dyn PrunePolicy>) {
+        self.prune_policy = prune_policy.into();
+    }
+
+    /// Sets how the final prune, or the prune over merged runs, differs from
+    /// the ones during training.
+    pub fn set_final_prune(&mut self, final_prune: FinalPrune) {
+        self.final_policy = final_prune.policy.map(Arc::from);
+        self.final_min_entries = final_prune.min_entries;
+        self.skip_final_prune = final_prune.skip;
+    }
+
+    /// Enables choosing a threshold at each prune to hit a target size.
+    pub fn set_auto_threshold(&mut self, auto_threshold: Option<AutoThreshold>) {
+        self.auto_threshold = auto_threshold;
+    }
+
+    pub fn auto_threshold(&self) -> Option<&AutoThreshold> {
+        self.auto_threshold.as_ref()
+    }
+
+    /// Only inserts states admitted by `prefilter`, which should have seen
+    /// the whole input already.
+    pub fn set_prefilter(&mut self, prefilter: Option<Prefilter>) {
+        self.prefilter = prefilter;
+    }
+
+    pub fn prefilter(&self) -> Option<&Prefilter> {
+        self.prefilter.as_ref()
+    }
+
+    /// Leaves `stop_words` out of topic selection, and records their mode
+    /// in the model. The chain keeps whatever words it is given, so with
+    /// `StopWordMode::Everywhere` they must already be filtered out of them,
+    /// as `StopWords::filter` does.
+    pub fn set_stop_words(&mut self, stop_words: StopWords) {
+        self.stop_words = stop_words;
+    }
+
+    /// Sets whether sentence ends break the chain and measure the topic
+    /// window.
+    pub fn set_sentence_options(&mut self, sentences: SentenceOptions) {
+        self.sentences = sentences;
+    }
+
+    /// Records the name of the tokenizer in the model's metadata.
+    pub fn set_tokenizer_name(&mut self, tokenizer: Option<String>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Records the hash of the normalization rules in the model's metadata.
+    pub fn set_rules_hash(&mut self, rules_hash: Option<String>) {
+        self.rules_hash = rules_hash;
+    }
+
+    /// Caps each successor list at `sample_size` observations, replacing
+    /// them by reservoir sampling so that every observation is equally
+    /// likely to be kept. The true counts are still tracked.
+    pub fn set_sample_size(&mut self, sample_size: Option<usize>) {
+        self.sample_size = sample_size;
+    }
+
+    pub fn sample_size(&self) -> Option<usize> {
+        self.sample_size
+    }
+
+    /// Seeds the random numbers used for sampling, so that training the
+    /// same input again gives the same model. Unseeded chains use entropy.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Caps the memory used by the chain at `max_memory` bytes, including the
+    /// copy made while pruning. The chain prunes early, and harder than its
+    /// policy if it has to, to stay under the cap. `update` fails with
+    /// `Error::MemoryCap` when it cannot.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        self.max_memory = max_memory;
+    }
+
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_memory
+    }
+
+    // Most the active pool may hold under the memory cap: the next update may
+    // grow it as much as any before, and a prune may then copy all of it
+    // into another pool.
+    fn pool_limit(&self) -> usize {
+        match self.max_memory {
+            Some(max_memory) => (max_memory.saturating_sub(self.other_bytes()) / 2)
+                .saturating_sub(self.max_update_bytes),
+            None => usize::MAX,
+        }
+    }
+
+    /// Number of prunes done because the pool filled up.
+    pub fn num_prunes(&self) -> usize {
+        self.prune_history.len()
+    }
+
+    /// What each of those prunes dropped, in order.
+    pub fn prune_history(&self) -> &[PruneStats] {
+        &self.prune_history
+    }
+
+    /// Enables exact training: instead of pruning when the pool fills up, the
+    /// chain is written to a sorted run file in `dir` and cleared. Pruning is
+    /// then applied once by `merge_runs` over the combined counts.
+    pub fn spill_to<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        self.runs = Some(Runs::new(dir)?);
+        Ok(())
+    }
+
+    /// Number of run files written so far.
+    pub fn num_runs(&self) -> usize {
+        self.runs.as_ref().map_or(0, |runs| runs.len())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let runs = match self.runs.as_mut() {
+            Some(runs) => runs,
+            None => return Ok(()),
+        };
+
+        let mut entries: Vec<_> = self.chain.iter().collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| {
+            (a.0.as_str(), a.1.as_str()).cmp(&(b.0.as_str(), b.1.as_str()))
+        });
+
+        let mut run = runs.create()?;
+        for ((s1, s2), state) in entries {
+            run.write_state((s1.as_str(), s2.as_str()), state.topics.len())?;
+
+            for ((t1, t2), observations) in state.topics.iter() {
+                run.write_topic(
+                    (t1.as_str(), t2.as_str()),
+                    observations.list.len(),
+                    observations.seen,
+                )?;
+
+                for (seq_num, successor) in observations.list.iter() {
+                    run.write_observation(*seq_num, successor.as_ref().map(|s| s.as_str()))?;
+                }
+            }
+        }
+
+        run.finish()?;
+
+        let oldest = self.oldest_generation();
+        mem::swap(&mut self.spare_pool, &mut self.generation_pools[oldest]);
+
+        let new_chain = self.new_hash_map(self.prune_size / 1000, self.generation_pool(oldest));
+        unsafe { self.replace_chain(new_chain) }
+
+        Ok(())
+    }
+
+    /// Writes out what is left in memory and merges every run into a model
+    /// file written to `writer`, pruning over the exact counts. Returns what
+    /// that prune dropped, or `None` if nothing was ever spilled, in which
+    /// case nothing is written and the chain still holds everything.
+    ///
+    /// Entries are written as they are merged, so the model is never held in
+    /// memory. An automatic threshold or a minimum number of entries needs
+    /// the histogram of the merged counts first, so then the runs are merged
+    /// into a single one on disk, which is read back to be written out.
+    pub fn merge_runs<W: Write>(&mut self, writer: &mut W) -> Result<Option<PruneStats>> {
+        if self.num_runs() == 0 {
+            return Ok(None);
+        }
+
+        if self.num_entries() > 0 {
+            self.spill()?;
+        }
+
+        let mut runs = self.runs.take().unwrap();
+        let metric = self.threshold_metric();
+
+        let (policy, min_entries): (Arc<dyn PrunePolicy>, _) = match self.skip_final_prune {
+            true => (Arc::new(Threshold::new(metric, 0)), 0),
+            false => (self.final_policy(), self.final_min_entries),
+        };
+
+        let mut auto_threshold = match self.skip_final_prune {
+            true => None,
+            false => self.auto_threshold.as_mut(),
+        };
+
+        let mut threshold = None;
+        let mut floor = None;
+
+        if auto_threshold.is_some() || min_entries > 0 {
+            let (mut kept, mut all) = (Histogram::new(), Histogram::new());
+            runs.compact(
+                self.sample_size,
+                StdRng::seed_from_u64(self.rng.gen()),
+                |bigram, topic_map, counts| {
+                    let value =
+                        prune::with_kept_stats(topic_map, counts, &*policy, |s| metric.of(s));
+                    if let Some(value) = value {
+                        all.entry(value).or_insert((0, 0)).0 += 1;
+                    }
+
+                    let judged = prune::with_kept_stats(topic_map, counts, &*policy, |stats| {
+                        (metric.of(stats), policy.keep_state(stats))
+                    });
+
+                    if let Some((value, true)) = judged {
+                        let entry = kept.entry(value).or_insert((0, 0));
+
+                        entry.0 += 1;
+                        entry.1 +=
+                            owned_entry_bytes_where(bigram, topic_map, |topic, observations| {
+                                prune::keep_topic(&*policy, counts, topic, observations)
+                            });
+                    }
+                },
+            )?;
+
+            let min = match auto_threshold.as_mut() {
+                Some(auto_threshold) => auto_threshold.update(&kept, None),
+                None => 0,
+            };
+
+            threshold = Some(Threshold::new(metric, min));
+
+            let kept_entries: usize = kept.range(min..).map(|(_, (entries, _))| entries).sum();
+            if kept_entries < min_entries {
+                floor = Some(Threshold::new(metric, prune::floor(&all, min_entries)));
+            }
+        }
+
+        let mut policy: &dyn PrunePolicy = &*policy;
+
+        let with_threshold;
+        if let Some(threshold) = &threshold {
+            with_threshold = Both(policy, threshold);
+            policy = &with_threshold;
+        }
+
+        let with_floor;
+        if let Some(floor) = floor {
+            with_floor = Floor(policy, floor);
+            policy = &with_floor;
+        }
+
+        let stats = RefCell::new(PruneStats::default());
+        let observation_counts = RefCell::new(HashMap::new());
+
+        let entries = runs
+            .merge(self.sample_size, StdRng::seed_from_u64(self.rng.gen()))?
+            .filter_map(|entry| {
+                let (bigram, mut topic_map, mut counts) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                let mut stats = stats.borrow_mut();
+                stats.entries_before += 1;
+
+                if !prune::prune_recorded(&mut topic_map, &mut counts, policy, &mut stats) {
+                    return None;
+                }
+
+                stats.entries_after += 1;
+                if !counts.is_empty() {
+                    observation_counts
+                        .borrow_mut()
+                        .insert(bigram.clone(), counts);
+                }
+
+                Some(Ok((bigram, topic_map)))
+            });
+
+        model::write_model_streamed(writer, entries, || Metadata {
+            sample_size: self.sample_size,
+            observation_counts: observation_counts.take(),
+            tokenizer: self.tokenizer.clone(),
+            rules_hash: self.rules_hash.clone(),
+            stop_words: self.stop_words.sorted(),
+            stop_word_mode: Some(self.stop_words.mode()),
+            approximate: false,
+        })?;
+
+        Ok(Some(stats.into_inner()))
+    }
+
+    /// Prunes with the chain's own policy, as done when the pool fills up,
+    /// and adds what it dropped to the history. Fails if the memory cap
+    /// leaves no room for any state.
+    pub fn prune(&mut self) -> Result<PruneStats> {
+        let pool_bytes = self.prune_size;
+        let stats = self.prune_auto(Some(pool_bytes))?;
+
+        self.prune_history.push(stats.clone());
+        Ok(stats)
+    }
+
+    /// Prunes at the end of training, as set by `set_final_prune`. With an
+    /// automatic threshold, this applies the exact cutoff for the target
+    /// size.
+    pub fn prune_final(&mut self) -> Result<PruneStats> {
+        match self.skip_final_prune {
+            true => Ok(PruneStats {
+                entries_before: self.num_entries(),
+                entries_after: self.num_entries(),
+                ..PruneStats::default()
+            }),
+            false => self.prune_auto(None),
+        }
+    }
+
+    fn final_policy(&self) -> Arc<dyn PrunePolicy> {
+        self.final_policy
+            .clone()
+            .unwrap_or_else(|| self.prune_policy.clone())
+    }
+
+    // The metric extra thresholds are set on: the automatic threshold's, if
+    // any.
+    fn threshold_metric(&self) -> Metric {
+        self.auto_threshold
+            .as_ref()
+            .map_or(Metric::Topics, |auto_threshold| auto_threshold.metric)
+    }
+
+    fn prune_auto(&mut self, pool_bytes: Option<usize>) -> Result<PruneStats> {
+        let prune_policy = match pool_bytes {
+            Some(_) => self.prune_policy.clone(),
+            None => self.final_policy(),
+        };
+
+        let mut policy: &dyn PrunePolicy = &*prune_policy;
+
+        let auto = self.auto_threshold.take().map(|mut auto_threshold| {
+            let histogram = self.histogram(policy, auto_threshold.metric);
+            let threshold = Threshold::new(
+                auto_threshold.metric,
+                auto_threshold.update(&histogram, pool_bytes),
             );
 
-            if topic_bigram != previous_topic_bigram {
-                seq_num = 0;
-                previous_topic_bigram = topic_bigram.clone_in(pool);
+            self.auto_threshold = Some(auto_threshold);
+            threshold
+        });
+
+        let with_auto;
+        if let Some(threshold) = &auto {
+            with_auto = Both(policy, threshold);
+            policy = &with_auto;
+        }
+
+        let floor;
+        if pool_bytes.is_none() && self.final_min_entries > 0 {
+            let metric = self.threshold_metric();
+            let kept: usize = self
+                .histogram(policy, metric)
+                .values()
+                .map(|(e, _)| e)
+                .sum();
+
+            if kept < self.final_min_entries {
+                let all = self.histogram(&Floor::everything(policy, metric), metric);
+                let min = prune::floor(&all, self.final_min_entries);
+
+                floor = Floor(policy, Threshold::new(metric, min));
+                policy = &floor;
             }
+        }
+
+        let capped = match self.max_memory {
+            Some(max_memory) => {
+                // Survivors are copied beside the active pool. Before the
+                // end, they should also leave the chain room to double.
+                let mut budget = max_memory.saturating_sub(self.used_bytes());
+                if pool_bytes.is_some() {
+                    budget = min(budget, self.pool_limit() / 2);
+                }
+
+                self.cap_threshold(policy, budget)?
+            }
+            None => None,
+        };
+
+        let with_cap;
+        if let Some(threshold) = &capped {
+            with_cap = Both(policy, threshold);
+            policy = &with_cap;
+        }
+
+        Ok(match pool_bytes {
+            Some(_) => self.collect(self.collect_level(), policy),
+            None => self.prune_with(policy),
+        })
+    }
 
-            let next_unigram = words.get(i + 2).map(|&w| BUnigram::from_slice_in(w, pool));
+    // A threshold on top of `policy` for when its survivors would take more
+    // than `budget` bytes.
+    fn cap_threshold(&self, policy: &dyn PrunePolicy, budget: usize) -> Result<Option<Threshold>> {
+        let metric = self.threshold_metric();
 
-            self.chain
-                .entry((
-                    BUnigram::from_slice_in(words[i], pool),
-                    BUnigram::from_slice_in(words[i + 1], pool),
-                ))
-                .or_insert(BHashMap::with_hasher_in(self.hasher.clone(), pool))
-                .entry(topic_bigram)
-                .or_insert(BVec::new_in(pool))
-                .push((seq_num, next_unigram));
+        let histogram = self.histogram(policy, metric);
+        let kept_bytes: usize = histogram.values().map(|(_, bytes)| bytes).sum();
 
-            seq_num += 1;
+        if kept_bytes <= budget {
+            return Ok(None);
         }
 
-        if self.allocated_bytes() > self.prune_size {
-            self.prune();
+        let threshold = prune::cutoff(&histogram, usize::MAX, budget);
+        match histogram.keys().next_back() {
+            Some(&max_value) if threshold > max_value => {
+                Err(Error::MemoryCap(self.max_memory.unwrap_or(0)))
+            }
+            _ => Ok(Some(Threshold::new(metric, threshold))),
+        }
+    }
+
+    // Calls `f` for every state with at least one topic kept by `policy`,
+    // whether or not the state itself would be kept.
+    fn scan<'c, F>(&'c self, policy: &dyn PrunePolicy, mut f: F)
+    where
+        F: FnMut(&'c BBigram<'static>, &'c BTopicMap<'static>, &StateStats),
+    {
+        for (bigram, state) in self.chain.iter() {
+            with_stats(policy, &state.topics, |stats| {
+                f(bigram, &state.topics, stats)
+            });
+        }
+    }
+
+    fn histogram(&self, policy: &dyn PrunePolicy, metric: Metric) -> Histogram {
+        let mut histogram = Histogram::new();
+        self.scan(policy, |bigram, topic_map, stats| {
+            if policy.keep_state(stats) {
+                let entry = histogram.entry(metric.of(stats)).or_insert((0, 0));
+
+                entry.0 += 1;
+                entry.1 += entry_bytes(bigram, topic_map);
+            }
+        });
+
+        histogram
+    }
+
+    /// Prunes every generation with `policy`, copying the survivors into a
+    /// fresh pool.
+    pub fn prune_with(&mut self, policy: &dyn PrunePolicy) -> PruneStats {
+        let mut stats = PruneStats {
+            entries_before: self.num_entries(),
+            ..PruneStats::default()
+        };
+
+        let bytes_before = self.allocated_bytes();
+        let oldest = self.oldest_generation();
+        let new_pool = self.pool(self.spare_pool);
+
+        let mut new_chain = self.new_hash_map((self.num_entries() as f64 * 1.4) as usize, new_pool);
+        for (bigram, state) in self.chain.iter() {
+            if let Some(topics) = self.copy_state(&state.topics, policy, new_pool, &mut stats) {
+                let new_state = BState {
+                    generation: oldest,
+                    topics,
+                };
+
+                new_chain.insert(bigram.clone_in(new_pool), new_state);
+            }
         }
+
+        mem::swap(&mut self.spare_pool, &mut self.generation_pools[oldest]);
+        unsafe { self.replace_chain(new_chain) }
+
+        stats.entries_after = self.num_entries();
+        stats.bytes_reclaimed = bytes_before.saturating_sub(self.allocated_bytes());
+        stats
     }
 
-    fn new_hash_map<K: Hash + Eq, V>(&self, size: usize) -> BHashMap<'a, K, V> {
-        BHashMap::with_capacity_and_hasher_in(size, self.hasher.clone(), self.active_pool())
+    // The youngest generation whose collection leaves the older ones holding
+    // at most half the prune size, so that the prune frees enough room.
+    fn collect_level(&self) -> usize {
+        let oldest = self.oldest_generation();
+        (0..oldest)
+            .find(|&level| {
+                let older_bytes: usize = self.generation_pools[level + 1..]
+                    .iter()
+                    .map(|&id| self.pool(id).allocated_bytes())
+                    .sum();
+
+                older_bytes <= self.prune_size / 2
+            })
+            .unwrap_or(oldest)
     }
 
-    pub fn prune(&mut self) {
-        let old_pool_id = self.active_pool;
-        let new_pool = self.advance_pool();
+    // Prunes the generations up to `level` with `policy`, promoting their
+    // survivors to the next one. Older generations are left alone.
+    fn collect(&mut self, level: usize, policy: &dyn PrunePolicy) -> PruneStats {
+        if level == self.oldest_generation() {
+            return self.prune_with(policy);
+        }
+
+        let mut stats = PruneStats {
+            entries_before: self.num_entries(),
+            ..PruneStats::default()
+        };
+
+        let bytes_before = self.allocated_bytes();
 
-        let mut new_chain = self.new_hash_map((self.num_entries() as f64 * 1.4) as usize);
-        for (bigram, topic_map) in self
+        let promoted_to = level + 1;
+        let new_pool = self.generation_pool(promoted_to);
+
+        // Moved out, not dropped: their pools are reset below.
+        let young: Vec<_> = self
             .chain
-            .iter()
-            .filter(|(_, topic_map)| topic_map.len() >= self.prune_threshold)
-        {
-            let mut new_topic_map = self.new_hash_map(topic_map.len());
-            for (topic, unigrams) in topic_map.iter() {
-                let mut new_unigrams = BVec::with_capacity_in(unigrams.len(), new_pool);
-                for unigram in unigrams {
-                    let new_unigram = unigram.1.as_ref().map(|u| u.clone_in(new_pool));
-                    new_unigrams.push((unigram.0, new_unigram));
-                }
+            .drain_filter(|_, state| state.generation <= level)
+            .map(ManuallyDrop::new)
+            .collect();
 
-                new_topic_map.insert(topic.clone_in(new_pool), new_unigrams);
+        for entry in young.iter() {
+            let (bigram, state) = &**entry;
+            if let Some(topics) = self.copy_state(&state.topics, policy, new_pool, &mut stats) {
+                let new_state = BState {
+                    generation: promoted_to,
+                    topics,
+                };
+
+                self.chain.insert(bigram.clone_in(new_pool), new_state);
             }
+        }
+
+        for generation in 0..=level {
+            unsafe { self.reset_pool(self.generation_pools[generation]) }
+        }
+
+        stats.entries_after = self.num_entries();
+        stats.bytes_reclaimed = bytes_before.saturating_sub(self.allocated_bytes());
+        stats
+    }
 
-            new_chain.insert(bigram.clone_in(new_pool), new_topic_map);
+    // Copies what `policy` keeps of a state into `pool`, or returns `None` if
+    // it drops the state. What is dropped is recorded in `stats`.
+    fn copy_state(
+        &self,
+        topic_map: &BTopicMap<'static>,
+        policy: &dyn PrunePolicy,
+        pool: PoolRef,
+        stats: &mut PruneStats,
+    ) -> Option<BTopicMap<'static>> {
+        let observations: usize = topic_map.values().map(|o| o.seen).sum();
+        let kept = with_stats(policy, topic_map, |state_stats| {
+            policy.keep_state(state_stats).then(|| state_stats.topics())
+        });
+
+        match kept.flatten() {
+            Some(num_topics) => {
+                let new_topic_map = self.copy_topics(topic_map, policy, num_topics, pool);
+                let kept_observations: usize = new_topic_map.values().map(|o| o.seen).sum();
+
+                stats.observations_dropped += observations - kept_observations;
+                Some(new_topic_map)
+            }
+            None => {
+                stats.drop_state(topic_map.len(), observations);
+                None
+            }
         }
+    }
 
+    // Copies the topics of `topic_map` kept by `policy` into `pool`.
+    fn copy_topics(
+        &self,
+        topic_map: &BTopicMap<'static>,
+        policy: &dyn PrunePolicy,
+        num_topics: usize,
+        pool: PoolRef,
+    ) -> BTopicMap<'static> {
+        let mut new_topic_map = self.new_hash_map(num_topics, pool);
+        for (topic, unigrams) in topic_map.iter().filter(|(_, o)| {
+            policy.keep_topic(&TopicStats {
+                observations: o.seen,
+            })
+        }) {
+            let mut new_unigrams =
+                BObservations::with_capacity_in(unigrams.list.len(), unigrams.seen, pool);
+            for unigram in unigrams.list.iter() {
+                let new_unigram = unigram.1.as_ref().map(|u| u.clone_in(pool));
+                new_unigrams.list.push((unigram.0, new_unigram));
+            }
+
+            new_topic_map.insert(topic.clone_in(pool), new_unigrams);
+        }
+
+        new_topic_map
+    }
+
+    // `new_chain` must live in the oldest generation's pool. Every other pool
+    // is reset.
+    unsafe fn replace_chain(&mut self, mut new_chain: BChainMap<'static>) {
         mem::swap(&mut self.chain, &mut new_chain);
         mem::forget(new_chain);
 
-        unsafe { self.reset_pool(old_pool_id) }
+        let oldest = self.generation_pools[self.oldest_generation()];
+        for id in 0..self.pools.len() {
+            if id != oldest {
+                self.reset_pool(id);
+            }
+        }
     }
 
     unsafe fn reset_pool(&mut self, id: usize) {
@@ -186,26 +914,647 @@ impl<'a> Chain<'a> {
         self.chain.len()
     }
 
+    /// Bytes used in the pools of every generation, reachable or not.
     pub fn allocated_bytes(&self) -> usize {
-        self.active_pool().allocated_bytes()
+        self.generation_pools
+            .iter()
+            .map(|&id| self.pool(id).allocated_bytes())
+            .sum()
+    }
+
+    /// Bytes counted against the prune size: the generations' pools and the
+    /// memory outside the pools.
+    pub fn used_bytes(&self) -> usize {
+        self.allocated_bytes() + self.other_bytes()
+    }
+
+    // Bytes outside the pools, counted against the prune size along with the
+    // generations' pools.
+    fn other_bytes(&self) -> usize {
+        let counter_len = 2 * self.half_para_len;
+
+        size_of::<Chain>()
+            + self
+                .prefilter
+                .as_ref()
+                .map_or(0, |p| p.sketch().size_bytes())
+            + memory::table_bytes::<(&str, usize)>(counter_len)
+            + memory::vec_bytes::<(&str, usize)>(counter_len)
+    }
+
+    /// Breaks down the memory used by the chain. This scans the whole chain.
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport {
+            table_bytes: memory::table_bytes::<(BBigram, BState)>(self.chain.capacity()),
+            ..MemoryReport::default()
+        };
+
+        for (bigram, state) in self.chain.iter() {
+            report += entry_report(bigram, &state.topics);
+        }
+
+        report.unreachable_bytes = self.allocated_bytes().saturating_sub(report.live_bytes());
+        report.other_bytes = self.other_bytes();
+        report.reserved_bytes = self
+            .pools
+            .iter()
+            .map(|pool| {
+                let pool = unsafe { &*pool.get() };
+                pool.reserved_bytes()
+            })
+            .sum();
+
+        report
+    }
+
+    /// Observations of `state` under `topic`, or `None` if that pair was never
+    /// seen.
+    pub fn successors(&self, state: (&str, &str), topic: (&str, &str)) -> Option<Successors<'_>> {
+        let state = get_by_str(&self.chain, &self.hasher, state)?;
+        let observations = get_by_str(&state.topics, &self.hasher, topic)?;
+
+        Some(Successors::pooled(&observations.list))
+    }
+
+    /// Every topic seen for `state`, with the number of observations under it.
+    pub fn topics<'c>(
+        &'c self,
+        state: (&str, &str),
+    ) -> impl Iterator<Item = ((&'c str, &'c str), usize)> + 'c {
+        get_by_str(&self.chain, &self.hasher, state)
+            .into_iter()
+            .flat_map(|state| state.topics.iter())
+            .map(|((t1, t2), observations)| ((t1.as_str(), t2.as_str()), observations.seen))
+    }
+
+    /// Every state seen under `topic`, with the number of observations. This
+    /// scans the whole chain.
+    pub fn states<'c>(
+        &'c self,
+        topic: (&'c str, &'c str),
+    ) -> impl Iterator<Item = ((&'c str, &'c str), usize)> + 'c {
+        let hasher = &self.hasher;
+        self.chain.iter().filter_map(move |((s1, s2), state)| {
+            get_by_str(&state.topics, hasher, topic)
+                .map(|observations| ((s1.as_str(), s2.as_str()), observations.seen))
+        })
+    }
+
+    /// Whether `word` appears in any state bigram. This scans the whole chain,
+    /// so it takes time linear in the number of entries.
+    pub fn contains_word(&self, word: &str) -> bool {
+        self.chain
+            .keys()
+            .any(|(u1, u2)| u1.as_str() == word || u2.as_str() == word)
     }
 
     pub fn extract_map(&self) -> ChainMap {
         let mut new_chain = ChainMap::with_capacity(self.num_entries());
-        for ((b1, b2), topic_map) in self.chain.iter() {
-            let mut new_topic_map = HashMap::with_capacity(topic_map.len());
-            for ((t0, t1), unigrams) in topic_map.iter() {
-                let mut new_unigrams = Vec::with_capacity(unigrams.len());
-                for (u1, u2) in unigrams {
-                    new_unigrams.push((*u1, u2.as_ref().map(|u| u.into())));
+        for ((b1, b2), state) in self.chain.iter() {
+            new_chain.insert((b1.into(), b2.into()), owned_topic_map(&state.topics));
+        }
+
+        new_chain
+    }
+
+    /// Sampling settings and the true counts of sampled lists.
+    pub fn extract_metadata(&self) -> Metadata {
+        let mut observation_counts = HashMap::new();
+        for ((s1, s2), state) in self.chain.iter() {
+            let counts: ObservationCounts = state
+                .topics
+                .iter()
+                .filter(|(_, observations)| observations.seen > observations.list.len())
+                .map(|((t1, t2), observations)| ((t1.into(), t2.into()), observations.seen))
+                .collect();
+
+            if !counts.is_empty() {
+                observation_counts.insert((s1.into(), s2.into()), counts);
+            }
+        }
+
+        Metadata {
+            sample_size: self.sample_size,
+            observation_counts,
+            tokenizer: self.tokenizer.clone(),
+            rules_hash: self.rules_hash.clone(),
+            stop_words: self.stop_words.sorted(),
+            stop_word_mode: Some(self.stop_words.mode()),
+            approximate: false,
+        }
+    }
+
+    /// Writes the chain as a model file. Entries are copied out of the pools
+    /// one at a time, so the chain is never copied as a whole.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let entries = self.chain.iter().map(|((b1, b2), state)| {
+            let bigram: Bigram = (b1.into(), b2.into());
+            Ok((bigram, owned_topic_map(&state.topics)))
+        });
+
+        model::write_model_streamed(writer, entries, || self.extract_metadata())
+    }
+}
+
+/// What the topic window's `half_para_len` is measured in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopicWindow {
+    /// Words either side of the current one.
+    Words,
+    /// Sentences either side of the current one.
+    Sentences,
+    /// The whole line or document, whatever its length.
+    Document,
+}
+
+/// What sentence ends change in training, and how far topics look.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SentenceOptions {
+    /// Ends the chain at each sentence end, so that no state or successor
+    /// spans two sentences.
+    pub boundaries: bool,
+    /// What `half_para_len` counts when picking each word's topic.
+    pub window: TopicWindow,
+}
+
+impl Default for SentenceOptions {
+    fn default() -> Self {
+        SentenceOptions {
+            boundaries: false,
+            window: TopicWindow::Words,
+        }
+    }
+}
+
+impl SentenceOptions {
+    /// Whether lines need splitting into sentences at all.
+    pub fn any(&self) -> bool {
+        self.boundaries || self.window == TopicWindow::Sentences
+    }
+}
+
+/// Calls `f` with `(state, topic, sequence number, successor)` for every
+/// transition in a tokenized line. The topic is the two most frequent words
+/// of more than two letters, other than punctuation and `stop_words`,
+/// within the topic window, and the sequence number counts transitions
+/// since the topic last changed.
+///
+/// Sentences end before each index in `sentence_ends`; without any, the
+/// line is one sentence.
+pub(crate) fn for_each_transition<'w, F>(
+    words: &[&'w str],
+    sentence_ends: &[usize],
+    half_para_len: usize,
+    sentences: SentenceOptions,
+    stop_words: &HashSet<String>,
+    mut f: F,
+) where
+    F: FnMut((&'w str, &'w str), (&'w str, &'w str), i32, Option<&'w str>),
+{
+    let topical = |word: &str| {
+        word.chars().count() > 2
+            && word.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+            && !stop_words.contains(word)
+    };
+
+    let in_words = sentences.window == TopicWindow::Words;
+    if words.len() < 2 || (in_words && words.len() < half_para_len) {
+        return;
+    }
+
+    // Word index each sentence starts at, then the end of the line, and the
+    // sentence of each word.
+    let mut bounds = vec![0];
+    let mut sentence_of = Vec::new();
+    if sentences.any() {
+        bounds.extend(
+            sentence_ends
+                .iter()
+                .filter(|&&end| end > 0 && end < words.len()),
+        );
+        bounds.push(words.len());
+
+        // Ends may come in any order, or repeated.
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        sentence_of.reserve(words.len());
+        for (sentence, range) in bounds.windows(2).enumerate() {
+            sentence_of.resize(range[1], sentence);
+        }
+    }
+
+    let mut seq_num = 0;
+    let mut previous_topic_bigram = ("", "");
+
+    let mut counter = Counter::new();
+    let mut window = (0, 0);
+
+    for i in 0..(words.len() - 1) {
+        if !in_words {
+            let (start, end) = match sentences.window {
+                TopicWindow::Sentences => {
+                    let sentence = sentence_of[i];
+                    let last = min(sentence.saturating_add(half_para_len), bounds.len() - 2);
+                    (
+                        bounds[sentence.saturating_sub(half_para_len)],
+                        bounds[last + 1],
+                    )
+                }
+                _ => (0, words.len()),
+            };
+
+            for &word in words[window.0..min(start, window.1)].iter() {
+                if topical(word) {
+                    counter.remove(word);
+                }
+            }
+
+            for &word in words[window.1.max(start)..end].iter() {
+                if topical(word) {
+                    counter.add(word);
+                }
+            }
+
+            window = (start, end);
+
+            // Short sentences may leave too few words for a topic here but
+            // not further on.
+            if counter.total_count() < 3 || counter.num_items() < 2 {
+                continue;
+            }
+        } else {
+            let start = i.saturating_sub(half_para_len);
+            let end = min(i.saturating_add(half_para_len), words.len());
+
+            let para = &words[start..end];
+
+            if i == 0 {
+                for &word in para.iter().filter(|w| topical(w)) {
+                    counter.add(word);
+                }
+            } else {
+                if start > 0 {
+                    let word = words[start];
+                    if topical(word) {
+                        counter.remove(word);
+                    }
                 }
 
-                new_topic_map.insert((t0.into(), t1.into()), new_unigrams);
+                if end < words.len() || i + half_para_len == words.len() {
+                    let word = words[end - 1];
+                    if topical(word) {
+                        counter.add(word);
+                    }
+                }
             }
 
-            new_chain.insert((b1.into(), b2.into()), new_topic_map);
+            if counter.total_count() < 3 || counter.num_items() < 2 {
+                break;
+            }
         }
 
-        new_chain
+        if sentences.boundaries && sentence_of[i] != sentence_of[i + 1] {
+            continue;
+        }
+
+        let topic_bigram = (
+            counter.most_frequent(1).unwrap().0,
+            counter.most_frequent(2).unwrap().0,
+        );
+
+        if topic_bigram != previous_topic_bigram {
+            seq_num = 0;
+            previous_topic_bigram = topic_bigram;
+        }
+
+        let next = match words.get(i + 2) {
+            Some(_) if sentences.boundaries && sentence_of[i + 2] != sentence_of[i + 1] => None,
+            next => next.copied(),
+        };
+
+        f((words[i], words[i + 1]), topic_bigram, seq_num, next);
+
+        seq_num += 1;
+    }
+}
+
+fn hash_bigram(hasher: &ahash::RandomState, bigram: (&str, &str)) -> u64 {
+    let mut hasher = hasher.build_hasher();
+
+    unigram::hash_str(bigram.0, &mut hasher);
+    unigram::hash_str(bigram.1, &mut hasher);
+
+    hasher.finish()
+}
+
+fn bigram_eq<'b>(bigram: (&'b str, &'b str)) -> impl Fn(&BBigram) -> bool + 'b {
+    move |(u1, u2)| u1.as_str() == bigram.0 && u2.as_str() == bigram.1
+}
+
+fn bigram_bytes(bigram: &BBigram) -> usize {
+    bigram.0.heap_bytes() + bigram.1.heap_bytes()
+}
+
+// Calls `f` with the statistics of `topic_map` after per-topic pruning by
+// `policy`, unless no topic is kept.
+fn with_stats<R, F>(policy: &dyn PrunePolicy, topic_map: &BTopicMap, f: F) -> Option<R>
+where
+    F: FnOnce(&StateStats) -> R,
+{
+    let keep_topic = |observations: &BObservations| {
+        policy.keep_topic(&TopicStats {
+            observations: observations.seen,
+        })
+    };
+
+    let (num_topics, num_observations) = topic_map
+        .values()
+        .filter(|observations| keep_topic(observations))
+        .fold((0, 0), |(t, o), observations| {
+            (t + 1, o + observations.seen)
+        });
+
+    if num_topics == 0 {
+        return None;
+    }
+
+    let count_successors = || {
+        topic_map
+            .values()
+            .filter(|observations| keep_topic(observations))
+            .flat_map(|observations| observations.list.iter())
+            .map(|(_, successor)| successor.as_ref().map(|s| s.as_str()))
+            .collect::<HashSet<_>>()
+            .len()
+    };
+
+    Some(f(&StateStats::new(
+        num_topics,
+        num_observations,
+        &count_successors,
+    )))
+}
+
+// Pool bytes reachable from a chain entry, not counting its slot in the
+// chain's table.
+fn entry_report(bigram: &BBigram, topic_map: &BTopicMap) -> MemoryReport {
+    let mut report = MemoryReport {
+        key_bytes: bigram_bytes(bigram),
+        table_bytes: memory::table_bytes::<(BBigram, BObservations)>(topic_map.capacity()),
+        ..MemoryReport::default()
+    };
+
+    for (topic, observations) in topic_map.iter() {
+        report.key_bytes += bigram_bytes(topic);
+        report.key_bytes += observations
+            .list
+            .iter()
+            .filter_map(|(_, successor)| successor.as_ref())
+            .map(|successor| successor.heap_bytes())
+            .sum::<usize>();
+
+        report.observation_bytes +=
+            memory::vec_bytes::<(i32, Option<BUnigram>)>(observations.list.capacity());
+    }
+
+    report
+}
+
+// Pool bytes used by a chain entry, including its share of the chain's table.
+fn entry_bytes(bigram: &BBigram, topic_map: &BTopicMap) -> usize {
+    size_of::<(BBigram, BState)>() + entry_report(bigram, topic_map).live_bytes()
+}
+
+// As `entry_bytes`, for a new state with a single observation.
+fn new_entry_bytes(state: (&str, &str), successor: Option<&str>) -> usize {
+    // Tables start out with room for three entries and vectors for four.
+    size_of::<(BBigram, BState)>()
+        + unigram::heap_bytes(state.0.len())
+        + unigram::heap_bytes(state.1.len())
+        + memory::table_bytes::<(BBigram, BObservations)>(3)
+        + memory::vec_bytes::<(i32, Option<BUnigram>)>(4)
+        + successor.map_or(0, |s| unigram::heap_bytes(s.len()))
+}
+
+fn owned_topic_map(topic_map: &BTopicMap) -> TopicMap {
+    let mut new_topic_map = HashMap::with_capacity(topic_map.len());
+    for ((t0, t1), unigrams) in topic_map.iter() {
+        let mut new_unigrams = Vec::with_capacity(unigrams.list.len());
+        for (u1, u2) in unigrams.list.iter() {
+            new_unigrams.push((*u1, u2.as_ref().map(|u| u.into())));
+        }
+
+        new_topic_map.insert((t0.into(), t1.into()), new_unigrams);
+    }
+
+    new_topic_map
+}
+
+// As `entry_bytes`, for an entry that would be copied into a pool.
+pub(crate) fn owned_entry_bytes(bigram: &Bigram, topic_map: &TopicMap) -> usize {
+    owned_entry_bytes_where(bigram, topic_map, |_, _| true)
+}
+
+// As `owned_entry_bytes`, as if only the topics `keep` is true for were left.
+pub(crate) fn owned_entry_bytes_where<K>(bigram: &Bigram, topic_map: &TopicMap, keep: K) -> usize
+where
+    K: Fn(&Bigram, &[(i32, Option<Unigram>)]) -> bool,
+{
+    let str_bytes =
+        |(u1, u2): &Bigram| unigram::heap_bytes(u1.len()) + unigram::heap_bytes(u2.len());
+
+    let kept = topic_map
+        .iter()
+        .filter(|(topic, observations)| keep(topic, observations));
+
+    let mut bytes = size_of::<(BBigram, BState)>()
+        + str_bytes(bigram)
+        + memory::table_bytes::<(BBigram, BObservations)>(memory::table_capacity(
+            kept.clone().count(),
+        ));
+
+    for (topic, observations) in kept {
+        bytes += str_bytes(topic);
+        bytes += memory::vec_bytes::<(i32, Option<BUnigram>)>(observations.len());
+        bytes += observations
+            .iter()
+            .filter_map(|(_, successor)| successor.as_ref())
+            .map(|successor| unigram::heap_bytes(successor.len()))
+            .sum::<usize>();
+    }
+
+    bytes
+}
+
+fn pool_capacity(prune_size: usize) -> usize {
+    (prune_size as f64 * 1.1) as usize
+}
+
+fn get_by_str<'m, V>(
+    map: &'m BHashMap<'static, BBigram<'static>, V>,
+    hasher: &ahash::RandomState,
+    bigram: (&str, &str),
+) -> Option<&'m V> {
+    map.raw_entry()
+        .from_hash(hash_bigram(hasher, bigram), bigram_eq(bigram))
+        .map(|(_, v)| v)
+}
+
+// Only copies `bigram` into `pool` when it is not already present in `map`.
+fn get_or_insert_with<'m, V, F: FnOnce() -> V>(
+    map: &'m mut BHashMap<'static, BBigram<'static>, V>,
+    hasher: &ahash::RandomState,
+    bigram: (&str, &str),
+    default: F,
+    pool: PoolRef,
+) -> &'m mut V {
+    let hash = hash_bigram(hasher, bigram);
+
+    match map.raw_entry_mut().from_hash(hash, bigram_eq(bigram)) {
+        RawEntryMut::Occupied(entry) => entry.into_mut(),
+        RawEntryMut::Vacant(entry) => {
+            let key = (
+                BUnigram::from_slice_in(bigram.0, pool),
+                BUnigram::from_slice_in(bigram.1, pool),
+            );
+
+            entry.insert_hashed_nocheck(hash, key, default()).1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    // Lines of words drawn from a vocabulary of `vocabulary` words, the same
+    // for the same seed.
+    fn lines(count: usize, vocabulary: usize, seed: u64) -> Vec<Vec<String>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|_| {
+                (0..20)
+                    .map(|_| format!("word{}", rng.gen_range(0..vocabulary)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn update(chain: &mut Chain, line: &[String]) -> Result<()> {
+        let words: Vec<_> = line.iter().map(String::as_str).collect();
+        chain.update(&words)
+    }
+
+    fn chain(prune_size: usize) -> Chain {
+        Chain::new(
+            4,
+            prune_size,
+            Box::new(Threshold::new(Metric::Observations, 2)),
+        )
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let max_memory = 256 * 1024;
+
+        // The pool alone would never fill up, so only the cap prunes.
+        let mut chain = chain(4 * 1024 * 1024);
+        chain.set_max_memory(Some(max_memory));
+
+        for line in lines(1000, 500, 0) {
+            update(&mut chain, &line).unwrap();
+            assert!(chain.used_bytes() <= max_memory);
+        }
+
+        assert!(chain.num_prunes() > 0);
+        assert!(chain.prune_history()[0].entries_before > 0);
+
+        // A line too big to fit at all.
+        let line: Vec<_> = (0..50_000).map(|i| format!("unique{}", i)).collect();
+        match update(&mut chain, &line) {
+            Err(Error::MemoryCap(cap)) => assert_eq!(cap, max_memory),
+            result => panic!("expected a memory cap error, got {:?}", result.err()),
+        }
+
+        // What was kept still writes out as a model.
+        let mut file = Vec::new();
+        chain.write(&mut file).unwrap();
+
+        let model = Model::from_reader(&file[..]).unwrap();
+        assert_eq!(model.num_entries(), chain.num_entries());
+        assert!(model.num_entries() > 0);
+    }
+
+    // Trains with `generations`, collecting up to each level in turn and
+    // compacting every few lines. Older generations are left alone by a
+    // collection, so the policy must keep whatever it kept before, as an
+    // observation threshold with every topic kept does, for the result to
+    // match one generation's. Also returns every generation a state was in.
+    fn train_generations(generations: usize) -> (Chain, HashSet<usize>) {
+        let policy = Threshold::new(Metric::Observations, 2);
+
+        let mut chain = chain(1024 * 1024);
+        chain.set_generations(generations);
+
+        let mut seen = HashSet::new();
+        for (i, line) in lines(300, 60, 1).iter().enumerate() {
+            update(&mut chain, line).unwrap();
+            seen.extend(chain.chain.values().map(|state| state.generation));
+
+            if i % 7 == 6 {
+                chain.prune_with(&policy);
+
+                let oldest = chain.generation_pools[chain.oldest_generation()];
+                for id in (0..chain.pools.len()).filter(|&id| id != oldest) {
+                    assert_eq!(chain.pool(id).allocated_bytes(), 0);
+                }
+            } else if i % 3 == 2 {
+                let level = (i / 3) % generations;
+                chain.collect(level, &policy);
+
+                if level < chain.oldest_generation() {
+                    for generation in 0..=level {
+                        assert_eq!(chain.generation_pool(generation).allocated_bytes(), 0);
+                    }
+                }
+            }
+        }
+
+        assert_eq!(chain.num_prunes(), 0);
+        (chain, seen)
+    }
+
+    #[test]
+    fn test_generations() {
+        let (one, _) = train_generations(1);
+        let expected = one.extract_map();
+        assert!(one.num_entries() > 0);
+
+        for generations in 2..=4 {
+            let (chain, seen) = train_generations(generations);
+            assert_eq!(chain.generations(), generations);
+            assert_eq!(chain.num_entries(), one.num_entries());
+            assert_eq!(chain.extract_map(), expected);
+
+            for (state, topic_map) in expected.iter().take(20) {
+                let state = (state.0.as_str(), state.1.as_str());
+                for topic in topic_map.keys() {
+                    let topic = (topic.0.as_str(), topic.1.as_str());
+                    let counts: Vec<_> = chain.successors(state, topic).unwrap().counts().collect();
+                    assert_eq!(
+                        counts,
+                        one.successors(state, topic)
+                            .unwrap()
+                            .counts()
+                            .collect::<Vec<_>>()
+                    );
+                }
+            }
+
+            // Promotion has moved states through every generation.
+            assert_eq!(seen.len(), generations);
+        }
     }
 }
@@ -0,0 +1,92 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Pickle(serde_pickle::Error),
+    Regex(regex::Error),
+    InvalidPrunePolicy(String),
+    InvalidTokenizer(String),
+    InvalidRule(String),
+    RulesMismatch(String),
+    InvalidStopWords(String),
+    InvalidFormat(String),
+    InvalidApprox(String),
+    MemoryCap(usize),
+    EmptyModel(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Pickle(e) => write!(f, "model serialization error: {}", e),
+            Error::Regex(e) => write!(f, "invalid regex: {}", e),
+            Error::InvalidPrunePolicy(spec) => write!(f, "invalid prune policy: {}", spec),
+            Error::InvalidTokenizer(name) => write!(f, "unknown tokenizer: {}", name),
+            Error::InvalidRule(reason) => write!(f, "invalid rule: {}", reason),
+            Error::RulesMismatch(reason) => {
+                write!(f, "normalization rules don't match the model: {}", reason)
+            }
+            Error::InvalidStopWords(reason) => write!(f, "invalid stop words: {}", reason),
+            Error::InvalidFormat(reason) => write!(f, "invalid input format: {}", reason),
+            Error::InvalidApprox(reason) => write!(f, "invalid approximate counts: {}", reason),
+            Error::MemoryCap(bytes) => write!(
+                f,
+                "cannot stay under the memory cap of {:.3} GiB",
+                *bytes as f64 / bytesize::GIB as f64
+            ),
+            Error::EmptyModel(0) => write!(
+                f,
+                "training produced no entries; the input may be empty or every word filtered out"
+            ),
+            Error::EmptyModel(entries) => write!(
+                f,
+                "the final prune would drop all {} entries; lower the final threshold or set a minimum number of entries",
+                entries
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Pickle(e) => Some(e),
+            Error::Regex(e) => Some(e),
+            Error::InvalidPrunePolicy(_)
+            | Error::InvalidTokenizer(_)
+            | Error::InvalidRule(_)
+            | Error::RulesMismatch(_)
+            | Error::InvalidStopWords(_)
+            | Error::InvalidFormat(_)
+            | Error::InvalidApprox(_)
+            | Error::MemoryCap(_)
+            | Error::EmptyModel(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_pickle::Error> for Error {
+    fn from(e: serde_pickle::Error) -> Self {
+        Error::Pickle(e)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::Regex(e)
+    }
+}
@@ -0,0 +1,749 @@
+//! Reading training input as documents, decompressing it if needed.
+
+use crate::error::{Error, Result};
+
+use regex::Regex;
+
+use std::{
+    cell::Cell,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+};
+
+/// Compression formats, recognised by their magic bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    /// The format of a stream starting with `header`.
+    pub fn detect(header: &[u8]) -> Self {
+        match header {
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd,
+            [b'B', b'Z', b'h', ..] => Compression::Bzip2,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Compression::Xz,
+            _ => Compression::None,
+        }
+    }
+
+    /// Wraps `reader` in a decoder for this format. Concatenated streams
+    /// are decoded as one.
+    pub fn decoder<'r, R: BufRead + 'r>(self, reader: R) -> io::Result<Box<dyn BufRead + 'r>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => {
+                Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)))
+            }
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+            Compression::Bzip2 => {
+                Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(reader)))
+            }
+            Compression::Xz => Box::new(BufReader::new(
+                xz2::bufread::XzDecoder::new_multi_decoder(reader),
+            )),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "uncompressed"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Bzip2 => write!(f, "bzip2"),
+            Compression::Xz => write!(f, "xz"),
+        }
+    }
+}
+
+/// How much of an input file has been read, in bytes as stored on disk.
+#[derive(Clone)]
+pub struct Progress {
+    read: Rc<Cell<u64>>,
+    total: u64,
+}
+
+impl Progress {
+    pub fn read(&self) -> u64 {
+        self.read.get()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Percentage of the file read.
+    pub fn percent(&self) -> f64 {
+        match self.total {
+            0 => 100.0,
+            total => 100.0 * self.read() as f64 / total as f64,
+        }
+    }
+}
+
+// Counts the bytes read through it.
+struct Counting<R> {
+    inner: R,
+    read: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.set(self.read.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Opens `path`, decompressing it if it starts with the magic bytes of a
+/// known format. Progress counts compressed bytes.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Box<dyn BufRead>, Compression, Progress)> {
+    let file = File::open(path)?;
+    let progress = Progress {
+        read: Rc::new(Cell::new(0)),
+        total: file.metadata()?.len(),
+    };
+
+    let mut reader = BufReader::new(Counting {
+        inner: file,
+        read: progress.read.clone(),
+    });
+
+    let compression = Compression::detect(reader.fill_buf()?);
+    Ok((compression.decoder(reader)?, compression, progress))
+}
+
+/// How input lines are grouped into the documents passed to `update`.
+pub enum Grouping {
+    /// Every line is a document of its own.
+    Lines,
+    /// Runs of non-blank lines are documents, separated by blank lines.
+    Paragraphs,
+    /// Documents are separated by lines matching the regex, which are not
+    /// part of either.
+    Delimiter(Regex),
+}
+
+impl Grouping {
+    fn ends_document(&self, line: &str) -> bool {
+        match self {
+            Grouping::Lines => unreachable!("lines are read one at a time"),
+            Grouping::Paragraphs => line.trim().is_empty(),
+            Grouping::Delimiter(re) => re.is_match(line),
+        }
+    }
+}
+
+/// Input formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Lines of text, grouped into documents by a `Grouping`.
+    Plaintext,
+    /// One JSON object per line.
+    Jsonl,
+    Csv,
+    Tsv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "plaintext" => Ok(Format::Plaintext),
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(Error::InvalidFormat(format!("unknown format '{}'", name))),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Plaintext => write!(f, "plaintext"),
+            Format::Jsonl => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+            Format::Tsv => write!(f, "tsv"),
+        }
+    }
+}
+
+/// The fields of structured records to read. A field is a key in JSONL,
+/// with dots for nested objects, and a 1-based column number or, with a
+/// header, a column name in CSV and TSV.
+#[derive(Clone, Debug, Default)]
+pub struct Fields {
+    pub text: String,
+    /// Read alongside the text, and left empty where a record lacks them.
+    pub extra: Vec<String>,
+    /// Whether the first CSV or TSV row is a header.
+    pub header: bool,
+}
+
+/// A document's text, and the extra fields of its record in the order they
+/// were asked for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Document {
+    pub text: String,
+    pub fields: Vec<Option<String>>,
+}
+
+impl From<String> for Document {
+    fn from(text: String) -> Self {
+        Document {
+            text,
+            fields: Vec::new(),
+        }
+    }
+}
+
+enum Source<R> {
+    Plaintext(R, Grouping),
+    Jsonl(R, Fields),
+    // Reader, then the text column and extra columns.
+    Csv(csv::StringRecordsIntoIter<R>, usize, Vec<usize>),
+}
+
+/// Documents read from `reader`.
+///
+/// In plaintext, the non-blank lines of each are joined by spaces, and when
+/// grouping, empty documents are left out. A grouped document that reaches
+/// the maximum length is cut off at the end of a line, and the rest starts
+/// the next one. In structured formats, each record is a document, and
+/// malformed ones are counted, logged if asked, and skipped, as are lines
+/// that are not valid UTF-8 in any format.
+pub struct Documents<R> {
+    source: Source<R>,
+    // Lines read, and the last line of the latest document.
+    read: usize,
+    line: usize,
+    max_len: Option<usize>,
+    cut: usize,
+    malformed: usize,
+    malformed_log: Option<Box<dyn Write>>,
+}
+
+impl<R: BufRead> Documents<R> {
+    pub fn new(reader: R, grouping: Grouping) -> Self {
+        Self::from_source(Source::Plaintext(reader, grouping))
+    }
+
+    /// Documents from records in a structured `format`.
+    pub fn structured(reader: R, format: Format, fields: Fields) -> Result<Self> {
+        let delimiter = match format {
+            Format::Plaintext => {
+                return Err(Error::InvalidFormat("plaintext has no fields".to_string()))
+            }
+            Format::Jsonl => return Ok(Self::from_source(Source::Jsonl(reader, fields))),
+            Format::Csv => b',',
+            Format::Tsv => b'\t',
+        };
+
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .quoting(format == Format::Csv)
+            .has_headers(fields.header)
+            .flexible(true)
+            .from_reader(reader);
+
+        let headers = match fields.header {
+            true => Some(reader.headers().map_err(csv_error)?.clone()),
+            false => None,
+        };
+
+        let column = |field: &str| match (field.parse::<usize>(), &headers) {
+            (Ok(0), _) => Err(Error::InvalidFormat("columns start at 1".to_string())),
+            (Ok(column), _) => Ok(column - 1),
+            (Err(_), Some(headers)) => headers
+                .iter()
+                .position(|h| h == field)
+                .ok_or_else(|| Error::InvalidFormat(format!("no column named '{}'", field))),
+            (Err(_), None) => Err(Error::InvalidFormat(format!(
+                "column '{}' is not a number, and there is no header",
+                field
+            ))),
+        };
+
+        let text = column(&fields.text)?;
+        let extra = fields
+            .extra
+            .iter()
+            .map(|field| column(field))
+            .collect::<Result<_>>()?;
+
+        Ok(Self::from_source(Source::Csv(
+            reader.into_records(),
+            text,
+            extra,
+        )))
+    }
+
+    fn from_source(source: Source<R>) -> Self {
+        Documents {
+            source,
+            read: 0,
+            line: 0,
+            max_len: None,
+            cut: 0,
+            malformed: 0,
+            malformed_log: None,
+        }
+    }
+
+    /// Writes the line and reason of each malformed record to `log`.
+    pub fn set_malformed_log(&mut self, log: Option<Box<dyn Write>>) {
+        self.malformed_log = log;
+    }
+
+    /// Cuts grouped documents off once they reach `max_len` bytes, for
+    /// input whose separators never match.
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+    }
+
+    /// The last line of the latest document, or the line an error was
+    /// read at.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Number of documents cut off at the maximum length so far.
+    pub fn cut(&self) -> usize {
+        self.cut
+    }
+
+    /// Number of malformed records skipped so far.
+    pub fn malformed(&self) -> usize {
+        self.malformed
+    }
+
+    fn skip_malformed(&mut self, line: usize, reason: &str) -> io::Result<()> {
+        self.malformed += 1;
+        match &mut self.malformed_log {
+            Some(log) => writeln!(log, "line {}: {}", line, reason),
+            None => Ok(()),
+        }
+    }
+
+    // The next line of plaintext or JSONL, without its line ending, skipping
+    // lines that are not valid UTF-8.
+    fn next_line(&mut self) -> Option<io::Result<String>> {
+        loop {
+            let reader = match &mut self.source {
+                Source::Plaintext(reader, _) | Source::Jsonl(reader, _) => reader,
+                Source::Csv(..) => unreachable!(),
+            };
+
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return None,
+                Ok(_) => self.read += 1,
+                Err(e) => {
+                    self.line = self.read + 1;
+                    return Some(Err(e));
+                }
+            }
+
+            if line.ends_with(b"\n") {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+
+            match String::from_utf8(line) {
+                Ok(line) => return Some(Ok(line)),
+                Err(e) => {
+                    if let Err(e) = self.skip_malformed(self.read, &e.utf8_error().to_string()) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_plaintext(&mut self) -> Option<io::Result<Document>> {
+        let mut document = String::new();
+
+        while let Some(line) = self.next_line() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let grouping = match &self.source {
+                Source::Plaintext(_, grouping) => grouping,
+                _ => unreachable!(),
+            };
+
+            if let Grouping::Lines = grouping {
+                self.line = self.read;
+                return Some(Ok(line.into()));
+            }
+
+            if grouping.ends_document(&line) {
+                if document.is_empty() {
+                    continue;
+                }
+
+                return Some(Ok(document.into()));
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+
+            if !document.is_empty() {
+                document.push(' ');
+            }
+            document.push_str(line);
+            self.line = self.read;
+
+            if matches!(self.max_len, Some(max_len) if document.len() >= max_len) {
+                self.cut += 1;
+                return Some(Ok(document.into()));
+            }
+        }
+
+        match document.is_empty() {
+            true => None,
+            false => Some(Ok(document.into())),
+        }
+    }
+
+    fn next_jsonl(&mut self) -> Option<io::Result<Document>> {
+        loop {
+            let line = match self.next_line()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line = self.read;
+
+            let fields = match &self.source {
+                Source::Jsonl(_, fields) => fields,
+                _ => unreachable!(),
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let document = serde_json::from_str(&line)
+                .map_err(|e| e.to_string())
+                .and_then(|record: serde_json::Value| {
+                    let text = match json_field(&record, &fields.text) {
+                        Some(serde_json::Value::String(text)) => text.clone(),
+                        Some(_) => return Err(format!("field '{}' is not a string", fields.text)),
+                        None => return Err(format!("no field '{}'", fields.text)),
+                    };
+
+                    let fields = fields
+                        .extra
+                        .iter()
+                        .map(|field| match json_field(&record, field)? {
+                            serde_json::Value::Null => None,
+                            serde_json::Value::String(value) => Some(value.clone()),
+                            value => Some(value.to_string()),
+                        })
+                        .collect();
+
+                    Ok(Document { text, fields })
+                });
+
+            match document {
+                Ok(document) => return Some(Ok(document)),
+                Err(reason) => {
+                    if let Err(e) = self.skip_malformed(self.line, &reason) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_csv(&mut self) -> Option<io::Result<Document>> {
+        loop {
+            let (records, text, extra) = match &mut self.source {
+                Source::Csv(records, text, extra) => (records, *text, extra),
+                _ => unreachable!(),
+            };
+
+            let record = records.next()?;
+            let position = match &record {
+                Ok(record) => record.position(),
+                Err(e) => e.position(),
+            };
+            if let Some(position) = position {
+                self.line = position.line() as usize;
+            }
+
+            let document = match record {
+                Ok(record) => match record.get(text) {
+                    Some(value) => Ok(Document {
+                        text: value.to_string(),
+                        fields: extra
+                            .iter()
+                            .map(|&column| record.get(column).map(String::from))
+                            .collect(),
+                    }),
+                    None => Err(format!("no column {}", text + 1)),
+                },
+                Err(e) => {
+                    let reason = e.to_string();
+                    match e.into_kind() {
+                        csv::ErrorKind::Io(e) => return Some(Err(e)),
+                        _ => Err(reason),
+                    }
+                }
+            };
+
+            match document {
+                Ok(document) => return Some(Ok(document)),
+                Err(reason) => {
+                    if let Err(e) = self.skip_malformed(self.line, &reason) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Documents<R> {
+    type Item = io::Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.source {
+            Source::Plaintext(..) => self.next_plaintext(),
+            Source::Jsonl(..) => self.next_jsonl(),
+            Source::Csv(..) => self.next_csv(),
+        }
+    }
+}
+
+fn csv_error(e: csv::Error) -> Error {
+    let reason = e.to_string();
+    match e.into_kind() {
+        csv::ErrorKind::Io(e) => Error::Io(e),
+        _ => Error::InvalidFormat(reason),
+    }
+}
+
+// The value at `field`, a key or a dotted path of keys.
+fn json_field<'v>(record: &'v serde_json::Value, field: &str) -> Option<&'v serde_json::Value> {
+    record.get(field).or_else(|| {
+        field
+            .split('.')
+            .try_fold(record, |value, key| value.get(key))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn documents(input: &str, grouping: Grouping) -> Vec<String> {
+        Documents::new(input.as_bytes(), grouping)
+            .map(|document| document.unwrap().text)
+            .collect()
+    }
+
+    fn read_records(input: &str, format: Format, fields: Fields) -> (Vec<Document>, usize) {
+        read_records_bytes(input.as_bytes(), format, fields)
+    }
+
+    fn read_records_bytes(input: &[u8], format: Format, fields: Fields) -> (Vec<Document>, usize) {
+        let mut documents = Documents::structured(input, format, fields).unwrap();
+        let records = documents.by_ref().map(|d| d.unwrap()).collect();
+
+        (records, documents.malformed())
+    }
+
+    fn fields(text: &str, extra: &[&str], header: bool) -> Fields {
+        Fields {
+            text: text.to_string(),
+            extra: extra.iter().map(|field| field.to_string()).collect(),
+            header,
+        }
+    }
+
+    fn document(text: &str, fields: &[Option<&str>]) -> Document {
+        Document {
+            text: text.to_string(),
+            fields: fields.iter().map(|f| f.map(String::from)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_grouping() {
+        let input = "one\ntwo\n\n  \nthree\n---\nfour\n";
+
+        assert_eq!(
+            documents(input, Grouping::Lines),
+            vec!["one", "two", "", "  ", "three", "---", "four"]
+        );
+        assert_eq!(
+            documents(input, Grouping::Paragraphs),
+            vec!["one two", "three --- four"]
+        );
+        assert_eq!(
+            documents(input, Grouping::Delimiter(Regex::new("^-+$").unwrap())),
+            vec!["one two three", "four"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let input = b"one\ntw\xffo\nthree\r\n";
+        let mut documents = Documents::new(&input[..], Grouping::Lines);
+
+        let read: Vec<_> = documents.by_ref().map(|d| d.unwrap().text).collect();
+        assert_eq!(read, vec!["one", "three"]);
+        assert_eq!(documents.malformed(), 1);
+
+        let input = b"{\"text\": \"\xff\"}\n{\"text\": \"two\"}\n";
+        let (records, malformed) =
+            read_records_bytes(input, Format::Jsonl, fields("text", &[], false));
+        assert_eq!(records, vec![document("two", &[])]);
+        assert_eq!(malformed, 1);
+    }
+
+    #[test]
+    fn test_max_len() {
+        let mut documents = Documents::new(
+            "one
+two
+three
+
+four
+"
+            .as_bytes(),
+            Grouping::Paragraphs,
+        );
+        documents.set_max_len(Some(6));
+
+        let mut read = Vec::new();
+        while let Some(document) = documents.next() {
+            read.push((document.unwrap().text, documents.line()));
+        }
+
+        // Lines are those of each document's own last line, not of the
+        // separator after it.
+        assert_eq!(
+            read,
+            vec![
+                ("one two".to_string(), 2),
+                ("three".to_string(), 3),
+                ("four".to_string(), 5)
+            ]
+        );
+        assert_eq!(documents.cut(), 1);
+    }
+
+    #[test]
+    fn test_decompression() {
+        let text = "one\ntwo\n".repeat(100);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(text.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let zstd = zstd::encode_all(text.as_bytes(), 1).unwrap();
+
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        bzip2.write_all(text.as_bytes()).unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+        xz.write_all(text.as_bytes()).unwrap();
+        let xz = xz.finish().unwrap();
+
+        for (data, compression) in [
+            (text.as_bytes(), Compression::None),
+            (&gzip[..], Compression::Gzip),
+            (&zstd[..], Compression::Zstd),
+            (&bzip2[..], Compression::Bzip2),
+            (&xz[..], Compression::Xz),
+        ] {
+            assert_eq!(Compression::detect(data), compression);
+
+            // Concatenated streams decode as one.
+            let data = [data, data].concat();
+            let mut decoded = String::new();
+            compression
+                .decoder(&data[..])
+                .unwrap()
+                .read_to_string(&mut decoded)
+                .unwrap();
+
+            assert_eq!(decoded, text.repeat(2));
+        }
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let input = r#"{"body": "one", "meta": {"source": "a"}, "n": 1}
+            {"body": 2}
+
+            not json
+            {"body": "three", "meta": {}}
+        "#;
+
+        let (records, malformed) = read_records(
+            input,
+            Format::Jsonl,
+            fields("body", &["meta.source", "n"], false),
+        );
+        assert_eq!(
+            records,
+            vec![
+                document("one", &[Some("a"), Some("1")]),
+                document("three", &[None, None]),
+            ]
+        );
+        assert_eq!(malformed, 2);
+    }
+
+    #[test]
+    fn test_csv() {
+        let input = "id,label,body\n1,a,\"one, \"\"quoted\"\"\"\n2,b\n3,c,three\n";
+
+        let (records, malformed) = read_records(input, Format::Csv, fields("3", &["label"], true));
+        assert_eq!(
+            records,
+            vec![
+                document("one, \"quoted\"", &[Some("a")]),
+                document("three", &[Some("c")]),
+            ]
+        );
+        assert_eq!(malformed, 1);
+
+        let (records, _) = read_records(input, Format::Csv, fields("body", &[], true));
+        assert_eq!(records.len(), 2);
+
+        let input = "a\t\"b\n";
+        let (records, _) = read_records(input, Format::Tsv, fields("2", &[], false));
+        assert_eq!(records, vec![document("\"b", &[])]);
+
+        assert!(
+            Documents::structured(input.as_bytes(), Format::Tsv, fields("b", &[], false)).is_err()
+        );
+        assert!(
+            Documents::structured(input.as_bytes(), Format::Tsv, fields("0", &[], false)).is_err()
+        );
+    }
+}
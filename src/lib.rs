@@ -0,0 +1,35 @@
+#![feature(allocator_api, slice_ptr_get)]
+
+//! Topic-conditioned bigram Markov chains.
+//!
+//! A [`Chain`] is trained from lines split into words by a [`Tokenizer`] and
+//! written out as a pickled [`ChainMap`], which can be loaded back as a
+//! [`Model`] for generation. Both can be queried by `&str` without copying
+//! the model.
+
+mod clone_in;
+mod unigram;
+
+pub mod approx;
+pub mod chain;
+pub mod counter;
+pub mod error;
+pub mod input;
+pub mod memory;
+pub mod model;
+pub mod prune;
+pub mod query;
+pub mod rules;
+pub mod sample;
+pub mod sentence;
+pub mod sketch;
+pub mod spill;
+pub mod tokenizer;
+
+pub use chain::{Bigram, Chain, ChainMap, TopicMap, Unigram};
+pub use counter::Counter;
+pub use error::{Error, Result};
+pub use model::Model;
+pub use prune::PrunePolicy;
+pub use query::Successors;
+pub use tokenizer::{LineProcessor, Tokenizer};
@@ -1,29 +1,134 @@
-#![feature(allocator_api, slice_ptr_get)]
+use nessie::{
+    approx::{self, ApproxChain, SpaceSaving},
+    chain::{SentenceOptions, TopicWindow},
+    input::{self, Compression, Document, Documents, Fields, Format, Grouping, Progress},
+    prune::{AutoThreshold, FinalPrune, Metric, PolicySpec, PruneStats, PruneTotals, SizeTarget},
+    rules::Rules,
+    sentence::SentenceSplitter,
+    sketch::Prefilter,
+    tokenizer::{StopWordMode, StopWords, TokenizerKind, WordCounts, WordRanking},
+    Chain, Error, Model, Tokenizer,
+};
 
-mod chain;
-mod counter;
-mod unigram;
-mod clone_in;
-
-use chain::Chain;
-use clap::Clap;
-use deunicode::deunicode;
-use hashbrown::HashSet;
+use clap::{AppSettings, ArgSettings, Clap};
 use regex::Regex;
+use serde::Serialize;
 
-use std::fs::File;
-use std::io::{prelude::*, BufReader, BufWriter};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufWriter};
 use std::time::Instant;
 
+type InputDocuments = Documents<Box<dyn BufRead>>;
+
+// Distinct values counted per extra field. Past this, rare values are
+// replaced and counts become approximate.
+const FIELD_VALUES: usize = 10_000;
+
+// Options that approximate training has no use for.
+const APPROX_CONFLICTS: &[&str] = &[
+    "spill-dir",
+    "prefilter-min",
+    "target-entries",
+    "target-size-gib",
+    "max-memory-gib",
+    "prune-threshold",
+    "prune-policy",
+    "final-threshold",
+    "final-policy",
+    "skip-final-prune",
+    "final-min-entries",
+    "sample-size",
+    "seed",
+];
+
 #[derive(Clap)]
+#[clap(
+    setting = AppSettings::SubcommandsNegateReqs,
+    setting = AppSettings::ArgsNegateSubcommands
+)]
 struct Opts {
-    input: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    // Required unless a subcommand is given, which derive cannot say of an
+    // `Option`.
+    #[clap(setting = ArgSettings::Required)]
+    input: Option<String>,
+
+    /// "plaintext", "jsonl", "csv" or "tsv"
+    #[clap(long, default_value = "plaintext")]
+    format: Format,
+
+    /// JSONL field holding the text, with dots for nested objects
+    #[clap(long, default_value = "text")]
+    text_field: String,
+
+    /// CSV or TSV column holding the text, numbered from 1, or named with
+    /// --header
+    #[clap(long, default_value = "1")]
+    column: String,
+
+    /// Treats the first CSV or TSV row as a header
+    #[clap(long)]
+    header: bool,
+
+    /// Fields read alongside the text, in the same form as --text-field or
+    /// --column, counting documents by their values. May be repeated
+    #[clap(long)]
+    extra_field: Vec<String>,
+
+    /// Logs malformed records to this file instead of only counting them
+    #[clap(long)]
+    malformed_log: Option<String>,
+
+    /// Stops reading at an input error and keeps what was read, instead of
+    /// failing
+    #[clap(long)]
+    ignore_read_errors: bool,
 
     #[clap(short, long)]
     output: Option<String>,
 
+    /// Stop word files, with any number of words per line and lines starting
+    /// with # as comments. May be repeated
     #[clap(short, long)]
-    stop_words: String,
+    stop_words: Vec<String>,
+
+    /// Built-in stop words for a language, e.g. "english". May be repeated
+    #[clap(long)]
+    stop_word_list: Vec<String>,
+
+    /// Adds the most common words in the input as stop words, from a first
+    /// pass over it
+    #[clap(long)]
+    auto_stop_words: Option<usize>,
+
+    /// "frequency" ranks words by occurrences, "documents" by the number of
+    /// documents they occur in
+    #[clap(long, default_value = "frequency")]
+    auto_stop_words_by: WordRanking,
+
+    /// How many distinct words --auto-stop-words tracks. Counts are
+    /// approximate past this, but stay close for the most common words
+    #[clap(long, default_value = "100000")]
+    auto_stop_words_capacity: usize,
+
+    /// "default" strips punctuation, "whitespace" keeps it attached to words
+    /// and "punctuation" splits it into tokens of its own. These transliterate
+    /// to ASCII; "unicode" (or "unicode-nfc") keeps the original script
+    #[clap(long, default_value = "default")]
+    tokenizer: TokenizerKind,
+
+    /// Normalization rules, one per line. Line rules rewrite the raw line
+    /// before the tokenizer's own normalization, word rules its words after.
+    /// Replaces the default tokenizer's built-in rules
+    #[clap(long)]
+    rules: Option<String>,
+
+    /// Removes stop words from the chain too, instead of only leaving them
+    /// out of topics
+    #[clap(long)]
+    drop_stop_words: bool,
 
     #[clap(long, default_value = "10")]
     print_period: usize,
@@ -31,61 +136,765 @@ struct Opts {
     #[clap(long, default_value = "64")]
     half_para_len: usize,
 
+    /// Splits lines into sentences and ends the chain at each sentence end
+    #[clap(long)]
+    sentence_boundaries: bool,
+
+    /// Measures --half-para-len in sentences instead of words
+    #[clap(long)]
+    window_in_sentences: bool,
+
+    /// Makes the topic window span the whole line or document
+    #[clap(long, conflicts_with = "window-in-sentences")]
+    document_window: bool,
+
+    /// Groups lines into documents separated by blank lines, for hard-wrapped
+    /// text. The chain then runs across the lines of each document
+    #[clap(long)]
+    paragraphs: bool,
+
+    /// Groups lines into documents separated by lines matching this regex
+    #[clap(long, conflicts_with = "paragraphs")]
+    document_delimiter: Option<String>,
+
+    /// Cuts grouped documents off at this size, in case the separators never
+    /// match
+    #[clap(long, default_value = "16.0")]
+    max_document_mib: f64,
+
+    /// Abbreviations the sentence splitter doesn't end sentences at, one per
+    /// line, on top of its own
+    #[clap(long)]
+    abbreviations: Option<String>,
+
+    /// Ends sentences at a stop followed by whitespace whatever comes next,
+    /// for text that isn't capitalised
+    #[clap(long)]
+    sentences_any_case: bool,
+
     #[clap(long, default_value = "2.0")]
     prune_size_gib: f64,
 
+    /// Pool generations. With more than one, a prune promotes survivors
+    /// instead of copying every state, and only compacts the oldest
+    /// generation when the younger ones no longer free enough room
+    #[clap(long, default_value = "1")]
+    generations: usize,
+
+    /// Hard cap on training memory, including the copy made while pruning.
+    /// Prunes early and harder to stay under it, or stops with an error after
+    /// writing what it has
+    #[clap(long)]
+    max_memory_gib: Option<f64>,
+
+    /// Ignored when a target size is given
+    #[clap(long, default_value = "16")]
+    prune_threshold: usize,
+
+    /// Overrides --prune-threshold, e.g. "topics:16,observations:100"
+    #[clap(long)]
+    prune_policy: Option<PolicySpec>,
+
+    /// Threshold for the final prune only. Defaults to --prune-threshold
+    #[clap(long)]
+    final_threshold: Option<usize>,
+
+    /// Overrides --final-threshold
+    #[clap(long)]
+    final_policy: Option<PolicySpec>,
+
+    /// Keeps everything at the end instead of pruning once more
+    #[clap(long, conflicts_with_all = &["final-threshold", "final-policy"])]
+    skip_final_prune: bool,
+
+    /// Lowers the final threshold as far as needed to keep this many entries
+    #[clap(long, default_value = "0")]
+    final_min_entries: usize,
+
+    /// Writes the model even if it is empty, whether from the final prune or
+    /// the input
+    #[clap(long)]
+    allow_empty: bool,
+
+    /// Chooses a threshold on --auto-metric to end with this many entries
+    #[clap(long, conflicts_with = "target-size-gib")]
+    target_entries: Option<usize>,
+
+    /// Chooses a threshold on --auto-metric to end with a model this size
+    #[clap(long)]
+    target_size_gib: Option<f64>,
+
+    #[clap(long, default_value = "topics")]
+    auto_metric: Metric,
+
+    #[clap(long, default_value = "4.0")]
+    auto_headroom: f64,
+
+    /// Writes sorted runs here instead of pruning when memory fills up, then
+    /// prunes once over the exact counts
+    #[clap(long)]
+    spill_dir: Option<String>,
+
+    /// Caps each successor list at this many observations by reservoir
+    /// sampling
+    #[clap(long)]
+    sample_size: Option<usize>,
+
+    /// Seeds sampling, so that training again gives the same model
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Counts states in a first pass and only inserts those seen at least
+    /// this many times. Keep it at or below the prune thresholds
+    #[clap(long)]
+    prefilter_min: Option<u32>,
+
+    #[clap(long, default_value = "256")]
+    prefilter_mib: usize,
+
+    /// Keeps at most this many topics per state with approximate counts,
+    /// instead of pruning. Defaults to --approx-successors
+    #[clap(long, conflicts_with_all = APPROX_CONFLICTS)]
+    approx_topics: Option<usize>,
+
+    /// Keeps at most this many successors per topic with approximate counts.
+    /// Defaults to --approx-topics
+    #[clap(long, conflicts_with_all = APPROX_CONFLICTS)]
+    approx_successors: Option<usize>,
+
+    /// Sizes both of the above so that counts are off by at most this
+    /// fraction of the state's (or topic's) observations
+    #[clap(long, conflicts_with_all = APPROX_CONFLICTS)]
+    approx_error: Option<f64>,
+
+    /// Keeps at most this many states with approximate counts
+    #[clap(long, default_value = "1000000")]
+    approx_states: usize,
+
+    /// Writes what every prune dropped to this JSON file
+    #[clap(long)]
+    prune_report: Option<String>,
+
+    /// Prints where memory goes before and after the final prune
+    #[clap(long)]
+    memory_report: bool,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Prunes an existing model without retraining. The model is loaded
+    /// whole, pruned in place and written back out
+    Prune(PruneOpts),
+}
+
+#[derive(Clap)]
+struct PruneOpts {
+    input: String,
+
+    #[clap(short, long)]
+    output: String,
+
+    /// Ignored when a target size is given
     #[clap(long, default_value = "16")]
     prune_threshold: usize,
+
+    /// Overrides --prune-threshold, e.g. "topics:16,observations:100"
+    #[clap(long)]
+    prune_policy: Option<PolicySpec>,
+
+    /// Chooses a threshold on --auto-metric to end with this many entries
+    #[clap(long, conflicts_with = "target-size-gib")]
+    target_entries: Option<usize>,
+
+    /// Chooses a threshold on --auto-metric to end with a model whose
+    /// training chain would take this much memory
+    #[clap(long)]
+    target_size_gib: Option<f64>,
+
+    #[clap(long, default_value = "topics")]
+    auto_metric: Metric,
+
+    /// Lowers the threshold as far as needed to keep this many entries
+    #[clap(long, default_value = "0")]
+    min_entries: usize,
+
+    /// Writes the model even if pruning leaves it empty
+    #[clap(long)]
+    allow_empty: bool,
+
+    /// Writes what the prune dropped to this JSON file
+    #[clap(long)]
+    prune_report: Option<String>,
+}
+
+impl PruneOpts {
+    fn prune_policy(&self) -> PolicySpec {
+        let default_threshold = match self.auto_threshold() {
+            Some(_) => 1,
+            None => self.prune_threshold,
+        };
+
+        self.prune_policy
+            .clone()
+            .unwrap_or_else(|| PolicySpec::topics(default_threshold))
+    }
+
+    fn auto_threshold(&self) -> Option<AutoThreshold> {
+        let target = size_target(self.target_entries, self.target_size_gib)?;
+        Some(AutoThreshold::new(self.auto_metric, target, 1.0))
+    }
+}
+
+fn size_target(entries: Option<usize>, size_gib: Option<f64>) -> Option<SizeTarget> {
+    match (entries, size_gib) {
+        (Some(entries), _) => Some(SizeTarget::Entries(entries)),
+        (_, Some(size)) => Some(SizeTarget::Bytes((size * bytesize::GIB as f64) as usize)),
+        (None, None) => None,
+    }
+}
+
+impl Opts {
+    // Clap requires the input when there is no subcommand.
+    fn input(&self) -> &str {
+        self.input.as_deref().unwrap()
+    }
+
+    fn prune_policy(&self) -> PolicySpec {
+        let default_threshold = match self.auto_threshold() {
+            Some(_) => 1,
+            None => self.prune_threshold,
+        };
+
+        self.prune_policy
+            .clone()
+            .unwrap_or_else(|| PolicySpec::topics(default_threshold))
+    }
+
+    fn final_prune(&self) -> FinalPrune {
+        let policy = match (&self.final_policy, self.final_threshold) {
+            (Some(policy), _) => Some(policy.clone()),
+            (None, Some(threshold)) => Some(PolicySpec::topics(threshold)),
+            (None, None) => None,
+        };
+
+        FinalPrune {
+            policy: policy.map(|policy| policy.build()),
+            skip: self.skip_final_prune,
+            min_entries: self.final_min_entries,
+        }
+    }
+
+    fn stop_word_mode(&self) -> StopWordMode {
+        match self.drop_stop_words {
+            true => StopWordMode::Everywhere,
+            false => StopWordMode::Topics,
+        }
+    }
+
+    fn sentence_options(&self) -> SentenceOptions {
+        SentenceOptions {
+            boundaries: self.sentence_boundaries,
+            window: match (self.window_in_sentences, self.document_window) {
+                (true, _) => TopicWindow::Sentences,
+                (_, true) => TopicWindow::Document,
+                _ => TopicWindow::Words,
+            },
+        }
+    }
+
+    fn grouping(&self) -> nessie::Result<Grouping> {
+        Ok(match (&self.document_delimiter, self.paragraphs) {
+            (Some(delimiter), _) => Grouping::Delimiter(Regex::new(delimiter)?),
+            (None, true) => Grouping::Paragraphs,
+            (None, false) => Grouping::Lines,
+        })
+    }
+
+    fn fields(&self) -> Fields {
+        Fields {
+            text: match self.format {
+                Format::Jsonl => self.text_field.clone(),
+                _ => self.column.clone(),
+            },
+            extra: self.extra_field.clone(),
+            header: self.header,
+        }
+    }
+
+    fn documents(&self) -> nessie::Result<(InputDocuments, Compression, Progress)> {
+        let (reader, compression, progress) = input::open(self.input())?;
+
+        let mut documents = match self.format {
+            Format::Plaintext => Documents::new(reader, self.grouping()?),
+            _ if self.paragraphs || self.document_delimiter.is_some() => {
+                return Err(Error::InvalidFormat(format!(
+                    "{} records are documents already and can't be grouped",
+                    self.format
+                )));
+            }
+            format => Documents::structured(reader, format, self.fields())?,
+        };
+        documents.set_max_len(Some(
+            (self.max_document_mib * bytesize::MIB as f64) as usize,
+        ));
+
+        Ok((documents, compression, progress))
+    }
+
+    // Opened once, up front, and given to the training pass only, so that
+    // earlier passes over the input don't log the same records again.
+    fn malformed_log(&self) -> nessie::Result<Option<Box<dyn Write>>> {
+        Ok(match &self.malformed_log {
+            Some(path) => Some(Box::new(BufWriter::new(File::create(path)?))),
+            None => None,
+        })
+    }
+
+    // Capacities for approximate training, each defaulting to the other.
+    fn approx_capacities(&self) -> nessie::Result<Option<(usize, usize)>> {
+        let invalid = |reason: &str| Err(Error::InvalidApprox(reason.to_string()));
+
+        let default = match self.approx_error {
+            Some(epsilon) if !(epsilon > 0.0 && epsilon <= 1.0) => {
+                return invalid("--approx-error must be above 0 and at most 1")
+            }
+            epsilon => epsilon.map(approx::capacity_for_error),
+        };
+
+        let topics = self.approx_topics.or(default);
+        let successors = self.approx_successors.or(default);
+
+        let capacities = match (topics, successors) {
+            (None, None) => return Ok(None),
+            (Some(topics), None) => (topics, topics),
+            (None, Some(successors)) => (successors, successors),
+            (Some(topics), Some(successors)) => (topics, successors),
+        };
+
+        match capacities {
+            (0, _) | (_, 0) => invalid("capacities must be at least 1"),
+            _ if self.approx_states == 0 => invalid("--approx-states must be at least 1"),
+            capacities => Ok(Some(capacities)),
+        }
+    }
+
+    fn auto_threshold(&self) -> Option<AutoThreshold> {
+        let target = size_target(self.target_entries, self.target_size_gib)?;
+
+        Some(AutoThreshold::new(
+            self.auto_metric,
+            target,
+            self.auto_headroom,
+        ))
+    }
 }
 
 fn print_opts(opts: &Opts) {
     println!(
-        "input: {}, output: {}, stop words: {}",
-        opts.input,
+        "input: {}, output: {}",
+        opts.input(),
         opts.output.clone().unwrap_or_else(|| "none".to_string()),
-        opts.stop_words
     );
 
+    if opts.format != Format::Plaintext {
+        let fields = opts.fields();
+        println!(
+            "format: {}, text from {}, extra fields: {}",
+            opts.format,
+            fields.text,
+            match fields.extra.is_empty() {
+                true => "none".to_string(),
+                false => fields.extra.join(", "),
+            }
+        );
+    }
+
+    let mut stop_words: Vec<_> = opts.stop_words.clone();
+    stop_words.extend(
+        opts.stop_word_list
+            .iter()
+            .map(|l| format!("built-in {}", l)),
+    );
+    if let Some(n) = opts.auto_stop_words {
+        stop_words.push(format!("top {} by {}", n, opts.auto_stop_words_by));
+    }
+
+    match stop_words.is_empty() {
+        true => println!("stop words: none"),
+        false => println!("stop words: {}", stop_words.join(", ")),
+    }
+
+    println!("tokenizer: {}", opts.tokenizer);
+
+    match opts.stop_word_mode() {
+        StopWordMode::Topics => println!("stop words left out of topics only"),
+        StopWordMode::Everywhere => println!("stop words left out of the chain too"),
+    }
+
+    let window = match opts.sentence_options().window {
+        TopicWindow::Words => format!("{} words", opts.half_para_len),
+        TopicWindow::Sentences => format!("{} sentences", opts.half_para_len),
+        TopicWindow::Document => "whole document".to_string(),
+    };
+
+    println!(
+        "half paragraph length: {}, prune policy: {}, prune size: {} GiB",
+        window,
+        opts.prune_policy(),
+        opts.prune_size_gib
+    );
+
+    if opts.paragraphs {
+        println!("documents separated by blank lines");
+    } else if let Some(delimiter) = &opts.document_delimiter {
+        println!("documents separated by lines matching {}", delimiter);
+    }
+
+    if opts.paragraphs || opts.document_delimiter.is_some() {
+        println!("documents cut off at {} MiB", opts.max_document_mib);
+    }
+
+    if opts.sentence_boundaries {
+        println!("chain broken at sentence ends");
+    }
+
+    if opts.skip_final_prune {
+        println!("skipping the final prune");
+    } else if let Some(policy) = &opts.final_policy {
+        println!("final prune policy: {}", policy);
+    } else if let Some(threshold) = opts.final_threshold {
+        println!("final prune policy: {}", PolicySpec::topics(threshold));
+    }
+
+    if opts.final_min_entries > 0 {
+        println!("keeping at least {} entries", opts.final_min_entries);
+    }
+
+    if let Some(auto_threshold) = opts.auto_threshold() {
+        println!(
+            "automatic threshold: {:?} on {}, headroom: {}",
+            auto_threshold.target, auto_threshold.metric, auto_threshold.headroom
+        );
+    }
+
+    if let Some(spill_dir) = &opts.spill_dir {
+        println!("exact training, spilling to {}", spill_dir);
+    }
+
+    if let Some(sample_size) = opts.sample_size {
+        println!("sampling successor lists down to {}", sample_size);
+    }
+
+    if let Ok(Some((topics, successors))) = opts.approx_capacities() {
+        println!(
+            "approximate counts: at most {} states, {} topics per state, {} successors \
+             per topic",
+            opts.approx_states, topics, successors
+        );
+    }
+
+    if let Some(prefilter_min) = opts.prefilter_min {
+        println!(
+            "prefilter: at least {} observations, {} MiB sketch",
+            prefilter_min, opts.prefilter_mib
+        );
+    }
+}
+
+// At most the first `chars` characters of `line`.
+fn preview(line: &str, chars: usize) -> &str {
+    match line.char_indices().nth(chars) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
+
+// The tokenizer with stop words applied, and the sentence splitter if
+// lines are split into sentences.
+struct Tokens {
+    tokenizer: Box<dyn Tokenizer>,
+    stop_words: StopWords,
+    splitter: Option<SentenceSplitter>,
+}
+
+impl Tokens {
+    // Normalises a line, sentence by sentence if they are split.
+    fn normalize(&self, line: &str) -> Vec<String> {
+        match &self.splitter {
+            Some(splitter) => splitter
+                .split(line)
+                .into_iter()
+                .map(|sentence| self.tokenizer.normalize(sentence))
+                .collect(),
+            None => vec![self.tokenizer.normalize(line)],
+        }
+    }
+
+    // The words of a normalised line, and the index each sentence ends at.
+    fn words<'a>(&'a self, sentences: &'a [String]) -> (Vec<&'a str>, Vec<usize>) {
+        let mut words = Vec::new();
+        let mut sentence_ends = Vec::new();
+
+        for sentence in sentences {
+            let mut sentence = self.tokenizer.tokenize(sentence);
+            self.stop_words.filter(&mut sentence);
+
+            if !sentence.is_empty() {
+                words.append(&mut sentence);
+                sentence_ends.push(words.len());
+            }
+        }
+
+        (words, sentence_ends)
+    }
+}
+
+fn field_counts(opts: &Opts) -> Vec<SpaceSaving<String>> {
+    (0..opts.extra_field.len())
+        .map(|_| SpaceSaving::new(FIELD_VALUES))
+        .collect()
+}
+
+// Counts a document under the value of each of its extra fields.
+fn count_fields(field_counts: &mut [SpaceSaving<String>], document: &Document) {
+    for (counts, value) in field_counts.iter_mut().zip(&document.fields) {
+        let value = value.as_deref().unwrap_or("(none)");
+        counts.add(value, |v| v == value, || value.to_string());
+    }
+}
+
+fn print_input_summary(
+    opts: &Opts,
+    documents: &InputDocuments,
+    field_counts: &[SpaceSaving<String>],
+) {
+    if opts.format != Format::Plaintext || documents.malformed() > 0 {
+        println!("skipped {} malformed records", documents.malformed());
+    }
+
+    if documents.cut() > 0 {
+        println!(
+            "warning: cut off {} documents at {} MiB; check that documents are separated as expected",
+            documents.cut(),
+            opts.max_document_mib
+        );
+    }
+
+    for (field, counts) in opts.extra_field.iter().zip(field_counts) {
+        let values = match counts.len() {
+            FIELD_VALUES => format!("at least {} values, counts approximate", FIELD_VALUES),
+            len => format!("{} values", len),
+        };
+
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+
+        let top: Vec<_> = counts
+            .iter()
+            .take(10)
+            .map(|counted| format!("{}: {}", counted.key, counted.count))
+            .collect();
+
+        println!("documents by {} ({}): {}", field, values, top.join(", "));
+    }
+}
+
+// Fails on an input error, or under --ignore-read-errors, warns and lets
+// the caller stop reading.
+fn read_error(opts: &Opts, documents: &InputDocuments, e: io::Error) -> nessie::Result<()> {
+    let e = io::Error::new(e.kind(), format!("line {}: {}", documents.line(), e));
+    match opts.ignore_read_errors {
+        true => {
+            println!("\nwarning: stopped reading at {}", e);
+            Ok(())
+        }
+        false => Err(e.into()),
+    }
+}
+
+fn count_words(opts: &Opts, tokenizer: &dyn Tokenizer, n: usize) -> nessie::Result<WordCounts> {
+    let capacity = opts.auto_stop_words_capacity.max(n).max(1);
+    let mut counts = WordCounts::new(capacity, opts.auto_stop_words_by);
+
+    let mut documents = opts.documents()?.0;
+    while let Some(line) = documents.next() {
+        let line = match line {
+            Ok(line) => tokenizer.normalize(&line.text),
+            Err(e) => {
+                read_error(opts, &documents, e)?;
+                break;
+            }
+        };
+
+        counts.observe(&tokenizer.tokenize(&line));
+    }
+
+    Ok(counts)
+}
+
+fn prefilter_pass(opts: &Opts, tokens: &Tokens, min_count: u32) -> nessie::Result<Prefilter> {
+    let mut prefilter = Prefilter::new(min_count, opts.prefilter_mib * bytesize::MIB as usize);
+
+    let mut documents = opts.documents()?.0;
+    while let Some(line) = documents.next() {
+        let line = match line {
+            Ok(line) => tokens.normalize(&line.text),
+            Err(e) => {
+                read_error(opts, &documents, e)?;
+                break;
+            }
+        };
+
+        prefilter.observe(&tokens.words(&line).0);
+    }
+
+    Ok(prefilter)
+}
+
+// The prunes saved are only an estimate: pool bytes not spent on skipped
+// states, over the pool size. Prunes also free memory, so the real number
+// can be quite different.
+fn print_prefilter_savings(chain: &Chain, prune_size: usize) {
+    if let Some(prefilter) = chain.prefilter() {
+        println!(
+            "prefilter skipped {} observations, saving ~{:.3} GiB of pool \
+             (estimated ~{} prunes saved, {} done)",
+            prefilter.skipped_observations(),
+            prefilter.skipped_bytes() as f64 / bytesize::GIB as f64,
+            prefilter.skipped_bytes() / prune_size,
+            chain.num_prunes()
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct PruneReport<'r> {
+    prunes: &'r [PruneStats],
+    final_prune: &'r PruneStats,
+    total: PruneTotals,
+}
+
+fn print_prune_summary(report: &PruneReport) {
+    let dropped: Vec<_> = report
+        .total
+        .dropped_topics
+        .iter()
+        .map(|(topics, count)| format!("{}: {}", topics, count))
+        .collect();
+
     println!(
-        "half paragraph length: {}, prune threshold: {}, prune size: {} GiB",
-        opts.half_para_len, opts.prune_threshold, opts.prune_size_gib
+        "{} prunes in training, overall {}",
+        report.prunes.len(),
+        report.total
     );
+    println!("dropped entries by topic count: {}", dropped.join(", "));
 }
 
-struct LineProcessor<'a> {
-    special_chars_re: Regex,
-    stop_words: HashSet<&'a str>,
+fn print_auto_thresholds(chain: &Chain) {
+    if let Some(auto_threshold) = chain.auto_threshold() {
+        let thresholds: Vec<_> = auto_threshold
+            .thresholds()
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+
+        println!(
+            "effective {} thresholds: {} (final {})",
+            auto_threshold.metric,
+            thresholds.join(", "),
+            auto_threshold.threshold()
+        );
+    }
 }
 
-impl<'a> LineProcessor<'a> {
-    fn new(stop_words: &'a str) -> Self {
-        LineProcessor {
-            special_chars_re: Regex::new(r"[^\w\s]").unwrap(),
-            stop_words: stop_words.split_ascii_whitespace().collect(),
+fn train_approx(
+    opts: &Opts,
+    tokens: &Tokens,
+    (topics, successors): (usize, usize),
+    rules_hash: Option<String>,
+    malformed_log: Option<Box<dyn Write>>,
+) -> nessie::Result<()> {
+    let (mut documents, _, progress) = opts.documents()?;
+    documents.set_malformed_log(malformed_log);
+    let mut field_counts = field_counts(opts);
+    let mut chain = ApproxChain::new(opts.half_para_len, opts.approx_states, topics, successors);
+    chain.set_stop_words(tokens.stop_words.clone());
+    chain.set_sentence_options(opts.sentence_options());
+    chain.set_tokenizer_name(Some(opts.tokenizer.to_string()));
+    chain.set_rules_hash(rules_hash);
+
+    let start = Instant::now();
+
+    let mut i = 0;
+    while let Some(line) = documents.next() {
+        let line = match line {
+            Ok(line) => {
+                count_fields(&mut field_counts, &line);
+                tokens.normalize(&line.text)
+            }
+            Err(e) => {
+                read_error(opts, &documents, e)?;
+                break;
+            }
+        };
+
+        let (words, sentence_ends) = tokens.words(&line);
+        chain.update_sentences(&words, &sentence_ends);
+
+        if (i + 1) % opts.print_period == 0 {
+            print!(
+                "{:>7} ({:>5.1}%): {:>7} entries\r",
+                i + 1,
+                progress.percent(),
+                chain.num_entries()
+            );
         }
+
+        i += 1;
     }
 
-    fn sanitize(&self, line: &str) -> String {
-        let mut line = deunicode(line);
+    println!(
+        "\n\nfinished in {:.3}s, {} entries",
+        start.elapsed().as_secs_f64(),
+        chain.num_entries()
+    );
+
+    print_input_summary(opts, &documents, &field_counts);
 
-        line = self.special_chars_re.replace_all(&line, "").to_string();
-        line.make_ascii_lowercase();
+    // Nothing is pruned at the end, but the model is checked as if it had
+    // been, so that an empty one is refused the same way.
+    let stats = PruneStats {
+        entries_before: chain.num_entries(),
+        entries_after: chain.num_entries(),
+        ..PruneStats::default()
+    };
 
-        line.replace(" th ", " nth ")
+    if let Err(e) = check_final_prune(&stats, opts.allow_empty) {
+        println!("\n{}", e);
+        return Err(e);
     }
 
-    fn split<'b>(&self, line: &'b str) -> Vec<&'b str> {
-        line.split_ascii_whitespace()
-            .filter(|s| !self.stop_words.contains(s))
-            .collect()
+    if let Some(output) = &opts.output {
+        print!("writing to {}... ", output);
+
+        let mut writer = BufWriter::new(File::create(output)?);
+        chain.write(&mut writer)?;
+        writer.flush()?;
+
+        println!(
+            "{:.3}GiB written",
+            writer.get_ref().metadata()?.len() as f64 / bytesize::GIB as f64
+        );
     }
+
+    Ok(())
 }
 
 fn print_chain_info(chain: &Chain, newline: bool) {
     print!(
         "{:>7} entries, ~{:.3} GiB allocated\r",
         chain.num_entries(),
-        chain.allocated_bytes() as f64 / bytesize::GIB as f64
+        chain.used_bytes() as f64 / bytesize::GIB as f64
     );
 
     if newline {
@@ -93,54 +902,284 @@ fn print_chain_info(chain: &Chain, newline: bool) {
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let opts = Opts::parse();
+// Errors if the model would be empty, and warns if the final prune left
+// almost nothing, since that usually means the final threshold is off. An
+// empty chain before the prune usually means the input is.
+fn check_final_prune(stats: &PruneStats, allow_empty: bool) -> nessie::Result<()> {
+    if stats.entries_after == 0 && !allow_empty {
+        return Err(Error::EmptyModel(stats.entries_before));
+    }
+
+    if stats.entries_before == 0 {
+        return Ok(());
+    }
+
+    if stats.entries_after * 100 < stats.entries_before {
+        println!(
+            "\nWARNING: the final prune kept only {} of {} entries ({:.2}%); \
+             consider a lower threshold or a minimum number of entries\n",
+            stats.entries_after,
+            stats.entries_before,
+            stats.entries_after as f64 * 100.0 / stats.entries_before as f64
+        );
+    }
+
+    Ok(())
+}
+
+fn prune_model(opts: PruneOpts) -> nessie::Result<()> {
+    let policy = opts.prune_policy();
+    let mut auto_threshold = opts.auto_threshold();
+
+    println!("input: {}, output: {}", opts.input, opts.output);
+    println!(
+        "prune policy: {}, at least {} entries",
+        policy, opts.min_entries
+    );
+
+    if let Some(auto_threshold) = &auto_threshold {
+        println!(
+            "automatic threshold: {:?} on {}",
+            auto_threshold.target, auto_threshold.metric
+        );
+    }
+
+    print!("\nloading {}... ", opts.input);
+
+    let start = Instant::now();
+    let mut model = Model::load(&opts.input)?;
+
+    println!(
+        "{} entries in {:.3}s",
+        model.num_entries(),
+        start.elapsed().as_secs_f64()
+    );
+
+    let stats = model.prune_to(&*policy.build(), auto_threshold.as_mut(), opts.min_entries);
+    println!("prune: {}", stats);
+
+    if let Err(e) = check_final_prune(&stats, opts.allow_empty) {
+        println!("\n{}", e);
+        return Err(e);
+    }
+
+    let report = PruneReport {
+        prunes: &[],
+        final_prune: &stats,
+        total: PruneStats::total(Some(&stats)),
+    };
+
+    if let Some(path) = &opts.prune_report {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &report)
+            .map_err(io::Error::from)?;
+    }
+
+    if let Some(auto_threshold) = &auto_threshold {
+        println!(
+            "effective {} threshold: {}",
+            auto_threshold.metric,
+            auto_threshold.threshold()
+        );
+    }
+
+    print!("writing to {}... ", opts.output);
+
+    let mut writer = BufWriter::new(File::create(&opts.output)?);
+    model.write(&mut writer)?;
+    writer.flush()?;
+
+    println!(
+        "{:.3}GiB written",
+        writer.get_ref().metadata()?.len() as f64 / bytesize::GIB as f64
+    );
+
+    Ok(())
+}
+
+fn main() -> nessie::Result<()> {
+    let mut opts = Opts::parse();
+    if let Some(Command::Prune(prune_opts)) = opts.command.take() {
+        return prune_model(prune_opts);
+    }
+
+    let approx_capacities = opts.approx_capacities()?;
 
     print_opts(&opts);
     println!();
 
-    let stop_words = std::fs::read_to_string(opts.stop_words)?;
-    let stop_words = Regex::new(r"[^\w\s]")
-        .unwrap()
-        .replace_all(&stop_words, "")
-        .to_string();
+    let malformed_log = opts.malformed_log()?;
+
+    let mut rules = opts.tokenizer.default_rules();
+    let mut rules_hash = None;
+
+    if let Some(path) = &opts.rules {
+        let file_rules = Rules::from_file(path)?;
+        println!(
+            "{} rules from {}, hash {}",
+            file_rules.len(),
+            path,
+            file_rules.hash()
+        );
+
+        rules_hash = Some(file_rules.hash());
+        rules = Some(file_rules);
+    }
+
+    let tokenizer = opts.tokenizer.build_with(rules);
 
-    let line_processor = LineProcessor::new(&stop_words);
+    let mut stop_words = StopWords::new("", &*tokenizer, opts.stop_word_mode());
+    for path in &opts.stop_words {
+        stop_words.add_file(path, &*tokenizer)?;
+    }
+    for language in &opts.stop_word_list {
+        stop_words.add_language(language, &*tokenizer)?;
+    }
+
+    if let Some(n) = opts.auto_stop_words {
+        print!("counting words... ");
 
-    let input = File::open(opts.input)?;
-    let reader = BufReader::new(input);
+        let start = Instant::now();
+        let top = count_words(&opts, &*tokenizer, n)?.top(n);
+
+        println!(
+            "done in {:.3}s: {}",
+            start.elapsed().as_secs_f64(),
+            top.join(" ")
+        );
+        stop_words.extend(top);
+    }
+
+    let mut splitter = None;
+    if opts.sentence_options().any() {
+        let mut sentence_splitter = SentenceSplitter::new();
+        sentence_splitter.set_require_capital(!opts.sentences_any_case);
+        if let Some(path) = &opts.abbreviations {
+            sentence_splitter.add_abbreviations(std::fs::read_to_string(path)?.lines());
+        }
+
+        splitter = Some(sentence_splitter);
+    }
 
-    let mut chain = Chain::new(
-        opts.half_para_len,
-        (opts.prune_size_gib * (bytesize::GIB as f64)) as usize,
-        opts.prune_threshold,
+    let tokens = Tokens {
+        tokenizer,
+        stop_words,
+        splitter,
+    };
+
+    if let Some(capacities) = approx_capacities {
+        return train_approx(&opts, &tokens, capacities, rules_hash, malformed_log);
+    }
+
+    let prune_size = (opts.prune_size_gib * (bytesize::GIB as f64)) as usize;
+    let mut chain = Chain::new(opts.half_para_len, prune_size, opts.prune_policy().build());
+
+    if let Some(min_count) = opts.prefilter_min {
+        print!("counting states... ");
+
+        let start = Instant::now();
+        chain.set_prefilter(Some(prefilter_pass(&opts, &tokens, min_count)?));
+
+        println!("done in {:.3}s", start.elapsed().as_secs_f64());
+    }
+
+    let (mut documents, compression, progress) = opts.documents()?;
+    documents.set_malformed_log(malformed_log);
+    let mut field_counts = field_counts(&opts);
+    if compression != Compression::None {
+        println!("decompressing {} input", compression);
+    }
+
+    chain.set_generations(opts.generations);
+    chain.set_max_memory(
+        opts.max_memory_gib
+            .map(|size| (size * bytesize::GIB as f64) as usize),
     );
+    chain.set_auto_threshold(opts.auto_threshold());
+    chain.set_final_prune(opts.final_prune());
+    chain.set_sample_size(opts.sample_size);
+    if let Some(seed) = opts.seed {
+        chain.set_seed(seed);
+    }
+    chain.set_stop_words(tokens.stop_words.clone());
+    chain.set_sentence_options(opts.sentence_options());
+    chain.set_tokenizer_name(Some(opts.tokenizer.to_string()));
+    chain.set_rules_hash(rules_hash);
+
+    if let Some(spill_dir) = &opts.spill_dir {
+        chain.spill_to(spill_dir)?;
+    }
 
     let start = Instant::now();
     let mut section_times = (0f64, 0f64);
+    let mut num_logged_prunes = 0;
 
-    for (i, line) in reader.lines().enumerate() {
+    let mut i = 0;
+    while let Some(line) = documents.next() {
         let mut section_start = Instant::now();
 
         let line = match line {
-            Ok(line) => line_processor.sanitize(&line),
-            Err(_) => break,
+            Ok(line) => {
+                count_fields(&mut field_counts, &line);
+                tokens.normalize(&line.text)
+            }
+            Err(e) => {
+                read_error(&opts, &documents, e)?;
+                break;
+            }
         };
 
-        let words = line_processor.split(&line);
+        let (words, sentence_ends) = tokens.words(&line);
 
         section_times.0 += section_start.elapsed().as_secs_f64();
         section_start = Instant::now();
 
-        chain.update(&words);
+        if let Err(e) = chain.update_sentences(&words, &sentence_ends) {
+            println!("\n\n{} at line {}", e, documents.line());
+
+            if let (Error::MemoryCap(_), Some(output)) = (&e, &opts.output) {
+                print!("writing partial model to {}... ", output);
+
+                // With runs spilled, the partial model is their merge with
+                // what is left in memory, pruned as it would have been at
+                // the end.
+                let mut writer = BufWriter::new(File::create(output)?);
+                if chain.merge_runs(&mut writer)?.is_none() {
+                    chain.write(&mut writer)?;
+                }
+                writer.flush()?;
+
+                println!("done");
+            }
+
+            return Err(e);
+        }
 
         section_times.1 += section_start.elapsed().as_secs_f64();
 
+        if chain.num_prunes() > num_logged_prunes {
+            for stats in &chain.prune_history()[num_logged_prunes..] {
+                num_logged_prunes += 1;
+                println!(
+                    "\nprune {} at line {}: {}",
+                    num_logged_prunes,
+                    documents.line(),
+                    stats
+                );
+            }
+        }
+
         if (i + 1) % opts.print_period == 0 {
-            print!("{:>7}: {} ... ", i + 1, &line[0..72],);
+            print!(
+                "{:>7} ({:>5.1}%): {:<72} ... ",
+                i + 1,
+                progress.percent(),
+                preview(&line.join(" "), 72)
+            );
             print_chain_info(&chain, false);
             print!("\r");
         }
+
+        i += 1;
     }
 
     let duration = start.elapsed();
@@ -152,21 +1191,90 @@ fn main() -> std::io::Result<()> {
         section_times.1,
     );
 
-    chain.prune();
-    print_chain_info(&chain, true);
+    let (merged, final_prune) = match chain.num_runs() {
+        0 => {
+            if opts.memory_report {
+                print!("\n\nbefore final prune:\n{}", chain.memory_report());
+            }
 
-    if let Some(output) = opts.output {
-        print!("writing to {}... ", output);
+            let final_prune = chain.prune_final()?;
+            print_chain_info(&chain, true);
+
+            if opts.memory_report {
+                print!("\nafter final prune:\n{}", chain.memory_report());
+            }
+
+            (false, final_prune)
+        }
+        num_runs => {
+            // The merged model is written as it is merged, so it goes
+            // straight to the output.
+            let final_prune = match &opts.output {
+                Some(output) => {
+                    print!("merging {} runs into {}... ", num_runs, output);
+
+                    let mut writer = BufWriter::new(File::create(output)?);
+                    let final_prune = chain.merge_runs(&mut writer)?.unwrap();
+
+                    writer.flush()?;
+                    final_prune
+                }
+                None => {
+                    print!("merging {} runs... ", num_runs);
+                    chain.merge_runs(&mut io::sink())?.unwrap()
+                }
+            };
+
+            println!("{:>7} entries", final_prune.entries_after);
+            (true, final_prune)
+        }
+    };
+
+    println!("final prune: {}", final_prune);
+
+    if let Err(e) = check_final_prune(&final_prune, opts.allow_empty) {
+        println!("\n{}", e);
+
+        if let (true, Some(output)) = (merged, &opts.output) {
+            fs::remove_file(output)?;
+        }
+
+        return Err(e);
+    }
 
-        let output = File::create(output)?;
-        let mut writer = BufWriter::new(output);
+    let prunes = chain.prune_history();
+    let report = PruneReport {
+        prunes,
+        final_prune: &final_prune,
+        total: PruneStats::total(prunes.iter().chain(Some(&final_prune))),
+    };
+
+    print_prune_summary(&report);
+
+    if let Some(path) = &opts.prune_report {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &report)
+            .map_err(io::Error::from)?;
+    }
 
-        let chain_map = chain.extract_map();
-        serde_pickle::to_writer(&mut writer, &chain_map, true).unwrap();
+    print_auto_thresholds(&chain);
+    print_prefilter_savings(&chain, prune_size);
+    print_input_summary(&opts, &documents, &field_counts);
+
+    if let Some(output) = opts.output {
+        match merged {
+            true => print!("merged into {}: ", output),
+            false => {
+                print!("writing to {}... ", output);
+
+                let mut writer = BufWriter::new(File::create(&output)?);
+                chain.write(&mut writer)?;
+                writer.flush()?;
+            }
+        }
 
         println!(
             "{:.3}GiB written",
-            writer.get_ref().metadata()?.len() as f64 / bytesize::GIB as f64
+            fs::metadata(&output)?.len() as f64 / bytesize::GIB as f64
         );
     }
 
@@ -0,0 +1,269 @@
+use bumpalo::Bump;
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::Cell,
+    fmt,
+    mem::{align_of, size_of},
+    ops::AddAssign,
+    ptr::NonNull,
+};
+
+// SSE2 group width, which is also the control byte alignment.
+const GROUP_WIDTH: usize = 16;
+
+/// Where a chain's memory goes, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Out-of-line storage for state, topic and successor strings.
+    pub key_bytes: usize,
+    /// Observation vectors, including unused capacity.
+    pub observation_bytes: usize,
+    /// Hash table buckets and control bytes, including empty buckets.
+    pub table_bytes: usize,
+    /// Pool bytes nothing points to any more: outgrown tables and vectors,
+    /// and successors replaced by sampling. Only a prune reclaims them.
+    pub unreachable_bytes: usize,
+    /// Memory outside the pools: the chain itself, the prefilter sketch and
+    /// an upper bound on the topic window counter.
+    pub other_bytes: usize,
+    /// Memory held by the pools, including free space.
+    pub reserved_bytes: usize,
+}
+
+impl MemoryReport {
+    /// Bytes reachable from the chain.
+    pub fn live_bytes(&self) -> usize {
+        self.key_bytes + self.observation_bytes + self.table_bytes
+    }
+
+    /// Bytes used in the pools of every generation.
+    pub fn pool_bytes(&self) -> usize {
+        self.live_bytes() + self.unreachable_bytes
+    }
+
+    /// Bytes in use, inside the pools or not. This is what pruning is
+    /// triggered on.
+    pub fn total_bytes(&self) -> usize {
+        self.pool_bytes() + self.other_bytes
+    }
+}
+
+impl AddAssign for MemoryReport {
+    fn add_assign(&mut self, other: Self) {
+        self.key_bytes += other.key_bytes;
+        self.observation_bytes += other.observation_bytes;
+        self.table_bytes += other.table_bytes;
+        self.unreachable_bytes += other.unreachable_bytes;
+        self.other_bytes += other.other_bytes;
+        self.reserved_bytes += other.reserved_bytes;
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gib = |bytes: usize| bytes as f64 / bytesize::GIB as f64;
+        let rows = [
+            ("keys", self.key_bytes),
+            ("observations", self.observation_bytes),
+            ("hash tables", self.table_bytes),
+            ("unreachable", self.unreachable_bytes),
+            ("outside pools", self.other_bytes),
+            ("total", self.total_bytes()),
+            ("pools reserved", self.reserved_bytes),
+        ];
+
+        for (name, bytes) in rows.iter() {
+            writeln!(f, "{:>16}: {:>10.3} GiB", name, gib(*bytes))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes allocated by a hashbrown table of `T` with the given capacity.
+pub fn table_bytes<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+
+    let buckets = match capacity < 8 {
+        true => capacity + 1,
+        false => capacity / 7 * 8,
+    };
+
+    let ctrl_align = align_of::<T>().max(GROUP_WIDTH);
+    let ctrl_offset = (size_of::<T>() * buckets + ctrl_align - 1) & !(ctrl_align - 1);
+
+    ctrl_offset + buckets + GROUP_WIDTH
+}
+
+/// Capacity of a hashbrown table created to hold `len` entries.
+pub fn table_capacity(len: usize) -> usize {
+    let buckets = match len {
+        0 => return 0,
+        1..=3 => 4,
+        4..=7 => 8,
+        _ => (len * 8 / 7).next_power_of_two(),
+    };
+
+    match buckets < 8 {
+        true => buckets - 1,
+        false => buckets / 8 * 7,
+    }
+}
+
+/// Bytes allocated by a vector of `T` with the given capacity.
+pub fn vec_bytes<T>(capacity: usize) -> usize {
+    capacity * size_of::<T>()
+}
+
+/// A bump pool that also keeps the total size of its chunks, free space
+/// included. `Bump` only tells the size of the current chunk.
+pub(crate) struct Pool {
+    bump: Bump,
+    // Size of the current chunk, to notice when another is added.
+    chunk: Cell<usize>,
+    reserved: Cell<usize>,
+}
+
+impl Pool {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let bump = Bump::with_capacity(capacity);
+        let chunk = bump.chunk_capacity();
+
+        Pool {
+            bump,
+            chunk: Cell::new(chunk),
+            reserved: Cell::new(chunk),
+        }
+    }
+
+    /// Bytes allocated from the pool, including what is no longer used.
+    pub fn allocated_bytes(&self) -> usize {
+        self.bump.allocated_bytes()
+    }
+
+    /// Bytes held by the pool's chunks, including their free space.
+    pub fn reserved_bytes(&self) -> usize {
+        self.reserved.get()
+    }
+
+    /// Frees every chunk but the current one and empties it.
+    pub fn reset(&mut self) {
+        self.bump.reset();
+        self.chunk.set(self.bump.chunk_capacity());
+        self.reserved.set(self.chunk.get());
+    }
+
+    // Counts the chunk added by the last allocation, if any. A new chunk is
+    // larger than the last unless the system allocator refused that size, in
+    // which case it is missed.
+    fn track(&self) {
+        let chunk = self.bump.chunk_capacity();
+        if chunk != self.chunk.get() {
+            self.chunk.set(chunk);
+            self.reserved.set(self.reserved.get() + chunk);
+        }
+    }
+}
+
+unsafe impl Allocator for &Pool {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = (&self.bump).allocate(layout);
+        self.track();
+        ptr
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (&self.bump).deallocate(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = (&self.bump).grow(ptr, old_layout, new_layout);
+        self.track();
+        ptr
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        (&self.bump).shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::Global;
+
+    #[test]
+    fn test_table_bytes() {
+        assert_eq!(table_bytes::<u64>(0), 0);
+
+        // 4 buckets of 8 bytes, then 4 + 16 control bytes.
+        assert_eq!(table_bytes::<u64>(3), 32 + 4 + 16);
+
+        // 16 buckets hold 14 entries.
+        assert_eq!(table_bytes::<u64>(14), 128 + 16 + 16);
+    }
+
+    struct Counting(Cell<usize>);
+
+    unsafe impl Allocator for Counting {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.set(self.0.get() + layout.size());
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.set(self.0.get() - layout.size());
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_matches_hashbrown() {
+        let alloc = Counting(Cell::new(0));
+        let mut map = hashbrown::HashMap::with_hasher_in(ahash::RandomState::new(), &alloc);
+
+        for i in 0..1000u64 {
+            map.insert(i, [0u8; 24]);
+            assert_eq!(
+                alloc.0.get(),
+                table_bytes::<(u64, [u8; 24])>(map.capacity())
+            );
+        }
+
+        for len in 0..1000 {
+            let map = hashbrown::HashMap::<u64, u64>::with_capacity(len);
+            assert_eq!(map.capacity(), table_capacity(len));
+        }
+    }
+
+    #[test]
+    fn test_pool_reserved() {
+        let mut pool = Pool::with_capacity(1000);
+        let first = pool.reserved_bytes();
+        assert!(first >= 1000);
+
+        let mut v = Vec::new_in(&pool);
+        v.extend(0..10_000u64);
+        let grown = pool.reserved_bytes();
+        assert!(grown >= first + 80_000);
+        assert!(grown >= pool.allocated_bytes());
+        drop(v);
+
+        pool.reset();
+        assert!(pool.reserved_bytes() < grown);
+        assert_eq!(pool.allocated_bytes(), 0);
+    }
+}
@@ -0,0 +1,803 @@
+use crate::approx::{ApproxChainMap, ApproxTopics};
+use crate::chain::{self, Bigram, Chain, ChainMap, ObservationCounts, TopicMap};
+use crate::error::{Error, Result};
+use crate::prune::{
+    self, AutoThreshold, Both, Floor, Histogram, Metric, PrunePolicy, PruneStats, StateStats,
+    Threshold,
+};
+use crate::query::Successors;
+use crate::rules::Rules;
+use crate::tokenizer::{StopWordMode, Tokenizer, TokenizerKind};
+
+use hashbrown::{HashMap, HashSet};
+use rand::{seq::SliceRandom, Rng};
+use serde::{
+    de::{self, IgnoredAny, MapAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use std::{
+    cell::RefCell,
+    fmt,
+    fs::File,
+    hash::{BuildHasher, Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
+    iter,
+    path::Path,
+    result,
+};
+
+/// Training settings stored alongside the chain.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// Cap on each successor list, if lists were reservoir sampled.
+    pub sample_size: Option<usize>,
+
+    /// True observation counts of sampled lists, by state.
+    pub observation_counts: HashMap<Bigram, ObservationCounts>,
+
+    /// Name of the tokenizer the chain was trained with, which generated text
+    /// should be detokenized with.
+    pub tokenizer: Option<String>,
+
+    /// Hash of the normalization rules applied on top of the tokenizer, if
+    /// any. See `Rules::hash`.
+    pub rules_hash: Option<String>,
+
+    /// Stop words left out of topics, sorted. Whether they were also left
+    /// out of the chain is in `stop_word_mode`.
+    pub stop_words: Vec<String>,
+
+    /// Where the stop words were left out, or `None` for a model trained
+    /// before that was recorded.
+    pub stop_word_mode: Option<StopWordMode>,
+
+    /// Whether the chain was trained with approximate counts, which may be
+    /// overestimated, and has no sequence numbers. The counts and their
+    /// error bounds are then in the file's `approx` section, and the chain
+    /// lists each successor once.
+    pub approximate: bool,
+}
+
+#[derive(Serialize)]
+struct ModelFileRef<'m> {
+    chain: &'m ChainMap,
+    metadata: &'m Metadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approx: Option<&'m ApproxChainMap>,
+}
+
+// Models written before metadata was added are a bare chain map. Which one
+// a file holds is told from its first key, so that the chain is read
+// straight into its map rather than buffered to try each shape in turn.
+// A bare map reads as one with default metadata.
+struct ModelFile {
+    chain: ChainMap,
+    metadata: Metadata,
+    approx: Option<ApproxChainMap>,
+}
+
+impl From<ChainMap> for ModelFile {
+    fn from(chain: ChainMap) -> Self {
+        ModelFile {
+            chain,
+            metadata: Metadata::default(),
+            approx: None,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        deserializer.deserialize_map(ModelFileVisitor)
+    }
+}
+
+struct ModelFileVisitor;
+
+impl<'de> Visitor<'de> for ModelFileVisitor {
+    type Value = ModelFile;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a model file or a bare chain map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> result::Result<ModelFile, A::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Key {
+            Field(String),
+            State(Bigram),
+        }
+
+        match map.next_key::<Key>()? {
+            None => Ok(ChainMap::new().into()),
+            Some(Key::State(state)) => {
+                let mut chain = ChainMap::with_capacity(map.size_hint().unwrap_or(0) + 1);
+                chain.insert(state, map.next_value()?);
+
+                while let Some((state, topic_map)) = map.next_entry()? {
+                    chain.insert(state, topic_map);
+                }
+
+                Ok(chain.into())
+            }
+            Some(Key::Field(field)) => {
+                let (mut chain, mut metadata, mut approx) = (None, None, None);
+
+                let mut field = Some(field);
+                while let Some(name) = field {
+                    match name.as_str() {
+                        "chain" => chain = Some(map.next_value()?),
+                        "metadata" => metadata = Some(map.next_value()?),
+                        "approx" => approx = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+
+                    field = map.next_key()?;
+                }
+
+                Ok(ModelFile {
+                    chain: chain.ok_or_else(|| de::Error::missing_field("chain"))?,
+                    metadata: metadata.unwrap_or_default(),
+                    approx,
+                })
+            }
+        }
+    }
+}
+
+pub(crate) fn write_model<W: Write>(
+    writer: &mut W,
+    chain: &ChainMap,
+    metadata: &Metadata,
+    approx: Option<&ApproxChainMap>,
+) -> Result<()> {
+    let file = ModelFileRef {
+        chain,
+        metadata,
+        approx,
+    };
+
+    serde_pickle::to_writer(writer, &file, true)?;
+    Ok(())
+}
+
+// A model file whose chain is serialized from an iterator as it goes, and
+// whose metadata is only built once the chain has been written.
+struct StreamedModelFile<'a, I, M> {
+    entries: RefCell<Option<I>>,
+    metadata: RefCell<Option<M>>,
+    approx: Option<&'a ApproxChainMap>,
+    error: RefCell<Option<Error>>,
+}
+
+struct StreamedChain<'f, 'a, I, M>(&'f StreamedModelFile<'a, I, M>);
+
+impl<'a, K, V, I, M> Serialize for StreamedModelFile<'a, I, M>
+where
+    K: Serialize,
+    V: Serialize,
+    I: Iterator<Item = Result<(K, V)>>,
+    M: FnOnce() -> Metadata,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let fields = 2 + self.approx.is_some() as usize;
+
+        let mut file = serializer.serialize_struct("ModelFile", fields)?;
+        file.serialize_field("chain", &StreamedChain(self))?;
+
+        let metadata = self.metadata.borrow_mut().take().unwrap();
+        file.serialize_field("metadata", &metadata())?;
+
+        if let Some(approx) = self.approx {
+            file.serialize_field("approx", approx)?;
+        }
+        file.end()
+    }
+}
+
+impl<'f, 'a, K, V, I, M> Serialize for StreamedChain<'f, 'a, I, M>
+where
+    K: Serialize,
+    V: Serialize,
+    I: Iterator<Item = Result<(K, V)>>,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let entries = self.0.entries.borrow_mut().take().unwrap();
+        let error = &self.0.error;
+
+        serializer.collect_map(entries.map_while(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                *error.borrow_mut() = Some(e);
+                None
+            }
+        }))
+    }
+}
+
+/// Writes a model file with the chain serialized from `entries` one at a
+/// time, so that it never needs to be held in memory as a whole. `metadata`
+/// is called after the last entry. Stops at the first error from `entries`,
+/// leaving the file incomplete.
+pub(crate) fn write_model_streamed<W, K, V, I, M>(
+    writer: &mut W,
+    entries: I,
+    metadata: M,
+) -> Result<()>
+where
+    W: Write,
+    K: Serialize,
+    V: Serialize,
+    I: Iterator<Item = Result<(K, V)>>,
+    M: FnOnce() -> Metadata,
+{
+    write_model_streamed_with(writer, entries, metadata, None)
+}
+
+/// As `write_model_streamed`, followed by the counts of an approximate
+/// chain, if any.
+pub(crate) fn write_model_streamed_with<W, K, V, I, M>(
+    writer: &mut W,
+    entries: I,
+    metadata: M,
+    approx: Option<&ApproxChainMap>,
+) -> Result<()>
+where
+    W: Write,
+    K: Serialize,
+    V: Serialize,
+    I: Iterator<Item = Result<(K, V)>>,
+    M: FnOnce() -> Metadata,
+{
+    let file = StreamedModelFile {
+        entries: RefCell::new(Some(entries)),
+        metadata: RefCell::new(Some(metadata)),
+        approx,
+        error: RefCell::new(None),
+    };
+
+    let written = serde_pickle::to_writer(writer, &file, true);
+    match file.error.into_inner() {
+        Some(e) => Err(e),
+        None => Ok(written?),
+    }
+}
+
+/// A trained chain loaded into memory with owned, `String`-keyed maps.
+pub struct Model {
+    chain: ChainMap,
+    metadata: Metadata,
+    approx: Option<ApproxChainMap>,
+    vocabulary: HashSet<String>,
+}
+
+impl Model {
+    pub fn with_metadata(chain: ChainMap, metadata: Metadata) -> Self {
+        let vocabulary = vocabulary(&chain);
+
+        Model {
+            chain,
+            metadata,
+            approx: None,
+            vocabulary,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let file: ModelFile = serde_pickle::from_reader(reader)?;
+        Ok(Model {
+            approx: file.approx,
+            ..Model::with_metadata(file.chain, file.metadata)
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_model(writer, &self.chain, &self.metadata, self.approx.as_ref())
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.chain.len()
+    }
+
+    pub fn as_map(&self) -> &ChainMap {
+        &self.chain
+    }
+
+    pub fn into_map(self) -> ChainMap {
+        self.chain
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// For a model trained with approximate counts, every topic of `state`
+    /// with its count and error bound, and its successors likewise.
+    pub fn approx_topics(&self, state: (&str, &str)) -> Option<&ApproxTopics> {
+        get_by_str(self.approx.as_ref()?, state)
+    }
+
+    /// The tokenizer named in the metadata, to normalize text and
+    /// detokenize generated text with, the same way as in training. Models
+    /// that name none were trained with the default one.
+    ///
+    /// Rules are only stored as their hash, so a model trained with a rules
+    /// file needs the same `rules` given back. Without one, the tokenizer
+    /// has its default rules. Fails if the name is unknown, or if `rules`
+    /// don't match the hash in the metadata.
+    pub fn tokenizer(&self, rules: Option<&Rules>) -> Result<Box<dyn Tokenizer>> {
+        let kind: TokenizerKind = match &self.metadata.tokenizer {
+            Some(name) => name.parse()?,
+            None => TokenizerKind::Default,
+        };
+
+        match (&self.metadata.rules_hash, rules) {
+            (None, None) => Ok(kind.build()),
+            (Some(hash), Some(rules)) if *hash == rules.hash() => {
+                Ok(kind.build_with(Some(rules.clone())))
+            }
+            (Some(hash), Some(rules)) => Err(Error::RulesMismatch(format!(
+                "trained with rules {}, given {}",
+                hash,
+                rules.hash()
+            ))),
+            (Some(hash), None) => Err(Error::RulesMismatch(format!(
+                "trained with rules {}, given none",
+                hash
+            ))),
+            (None, Some(rules)) => Err(Error::RulesMismatch(format!(
+                "trained without a rules file, given {}",
+                rules.hash()
+            ))),
+        }
+    }
+
+    /// Prunes in place with `policy`. Bytes are as the entries would take in
+    /// a training chain.
+    pub fn prune(&mut self, policy: &dyn PrunePolicy) -> PruneStats {
+        let mut stats = PruneStats {
+            entries_before: self.chain.len(),
+            ..PruneStats::default()
+        };
+
+        let observation_counts = &mut self.metadata.observation_counts;
+        self.chain.retain(|state, topic_map| {
+            let bytes_before = chain::owned_entry_bytes(state, topic_map);
+            let mut counts = observation_counts.remove(state).unwrap_or_default();
+
+            if !prune::prune_recorded(topic_map, &mut counts, policy, &mut stats) {
+                stats.bytes_reclaimed += bytes_before;
+                return false;
+            }
+
+            stats.bytes_reclaimed += bytes_before - chain::owned_entry_bytes(state, topic_map);
+            if !counts.is_empty() {
+                observation_counts.insert(state.clone(), counts);
+            }
+
+            true
+        });
+
+        stats.entries_after = self.chain.len();
+        self.vocabulary = vocabulary(&self.chain);
+
+        if let Some(approx) = &mut self.approx {
+            let chain = &self.chain;
+            approx.retain(|state, topics| match chain.get(state) {
+                Some(topic_map) => {
+                    topics.retain(|topic| topic_map.contains_key(&topic.0));
+                    true
+                }
+                None => false,
+            });
+        }
+
+        stats
+    }
+
+    /// Prunes in place as the final prune of training would: with `policy`,
+    /// then the automatic threshold's exact cutoff, if any. When that keeps
+    /// fewer than `min_entries`, also keeps the states highest on the
+    /// threshold's metric up to `min_entries`, still filtering their topics
+    /// with `policy`.
+    pub fn prune_to(
+        &mut self,
+        policy: &dyn PrunePolicy,
+        auto_threshold: Option<&mut AutoThreshold>,
+        min_entries: usize,
+    ) -> PruneStats {
+        if auto_threshold.is_none() && min_entries == 0 {
+            return self.prune(policy);
+        }
+
+        let metric = auto_threshold
+            .as_ref()
+            .map_or(Metric::Topics, |auto_threshold| auto_threshold.metric);
+
+        // What `policy` keeps, and every state it leaves a topic, in one pass.
+        let (mut kept, mut all) = (Histogram::new(), Histogram::new());
+        self.scan(policy, |state, topic_map, counts, stats| {
+            let value = metric.of(stats);
+            all.entry(value).or_insert((0, 0)).0 += 1;
+
+            if policy.keep_state(stats) {
+                let entry = kept.entry(value).or_insert((0, 0));
+
+                entry.0 += 1;
+                entry.1 += kept_entry_bytes(policy, state, topic_map, counts);
+            }
+        });
+
+        let mut policy = policy;
+
+        let auto = auto_threshold
+            .map(|auto_threshold| Threshold::new(metric, auto_threshold.update(&kept, None)));
+
+        let with_auto;
+        if let Some(threshold) = &auto {
+            with_auto = Both(policy, threshold);
+            policy = &with_auto;
+        }
+
+        let min = auto.map_or(0, |threshold| threshold.min);
+        let kept_entries: usize = kept.range(min..).map(|(_, (entries, _))| entries).sum();
+
+        let floor;
+        if kept_entries < min_entries {
+            floor = Floor(
+                policy,
+                Threshold::new(metric, prune::floor(&all, min_entries)),
+            );
+            policy = &floor;
+        }
+
+        self.prune(policy)
+    }
+
+    /// Entries and their size in a training chain by value of `metric`, as
+    /// they would be after pruning with `policy`.
+    pub fn histogram(&self, policy: &dyn PrunePolicy, metric: Metric) -> Histogram {
+        let mut histogram = Histogram::new();
+        self.scan(policy, |state, topic_map, counts, stats| {
+            if policy.keep_state(stats) {
+                let entry = histogram.entry(metric.of(stats)).or_insert((0, 0));
+
+                entry.0 += 1;
+                entry.1 += kept_entry_bytes(policy, state, topic_map, counts);
+            }
+        });
+
+        histogram
+    }
+
+    // Calls `f` with every state that has a topic `policy` keeps, its topic
+    // map and true counts, and its statistics over the kept topics.
+    fn scan<F>(&self, policy: &dyn PrunePolicy, mut f: F)
+    where
+        F: FnMut(&Bigram, &TopicMap, &ObservationCounts, &StateStats),
+    {
+        let no_counts = ObservationCounts::new();
+        for (state, topic_map) in self.chain.iter() {
+            let counts = self
+                .metadata
+                .observation_counts
+                .get(state)
+                .unwrap_or(&no_counts);
+
+            prune::with_kept_stats(topic_map, counts, policy, |stats| {
+                f(state, topic_map, counts, stats)
+            });
+        }
+    }
+
+    pub fn topic_map(&self, state: (&str, &str)) -> Option<&TopicMap> {
+        get_by_str(&self.chain, state)
+    }
+
+    /// Raw `(sequence number, successor)` observations of `state` under
+    /// `topic`, as stored in the model file.
+    pub fn observations(
+        &self,
+        state: (&str, &str),
+        topic: (&str, &str),
+    ) -> Option<&[(i32, Option<String>)]> {
+        self.topic_map(state)
+            .and_then(|topic_map| get_by_str(topic_map, topic))
+            .map(|observations| observations.as_slice())
+    }
+
+    /// Observations of `state` under `topic`, or `None` if that pair was never
+    /// seen.
+    pub fn successors(&self, state: (&str, &str), topic: (&str, &str)) -> Option<Successors<'_>> {
+        self.observations(state, topic).map(Successors::owned)
+    }
+
+    /// Number of times `state` was observed under `topic` in training, which
+    /// may be more than `successors` holds if lists were sampled.
+    pub fn observation_count(&self, state: (&str, &str), topic: (&str, &str)) -> Option<usize> {
+        let len = self.observations(state, topic)?.len();
+        let count = get_by_str(&self.metadata.observation_counts, state)
+            .and_then(|counts| get_by_str(counts, topic))
+            .copied();
+
+        Some(count.unwrap_or(len))
+    }
+
+    /// Every topic seen for `state`, with the number of observations under it.
+    pub fn topics<'m>(
+        &'m self,
+        state: (&str, &str),
+    ) -> impl Iterator<Item = ((&'m str, &'m str), usize)> + 'm {
+        let counts = get_by_str(&self.metadata.observation_counts, state);
+
+        self.topic_map(state)
+            .into_iter()
+            .flat_map(|topic_map| topic_map.iter())
+            .map(move |(topic, observations)| {
+                let count = counts.and_then(|counts| counts.get(topic)).copied();
+                (
+                    (topic.0.as_str(), topic.1.as_str()),
+                    count.unwrap_or(observations.len()),
+                )
+            })
+    }
+
+    /// Every state seen under `topic`, with the number of observations. This
+    /// scans the whole model.
+    pub fn states<'m>(
+        &'m self,
+        topic: (&'m str, &'m str),
+    ) -> impl Iterator<Item = ((&'m str, &'m str), usize)> + 'm {
+        self.chain.keys().filter_map(move |(s1, s2)| {
+            let state = (s1.as_str(), s2.as_str());
+            self.observation_count(state, topic)
+                .map(|count| (state, count))
+        })
+    }
+
+    /// Whether `word` appears in any state bigram.
+    pub fn contains_word(&self, word: &str) -> bool {
+        self.vocabulary.contains(word)
+    }
+
+    pub fn vocabulary(&self) -> impl Iterator<Item = &str> {
+        self.vocabulary.iter().map(|word| word.as_str())
+    }
+
+    /// Generates up to `max_words` words following `start` under `topic`,
+    /// stopping early at the end of a line or when the chain has no
+    /// successors for the current state. The returned words include `start`.
+    pub fn generate<'m, R: Rng + ?Sized>(
+        &'m self,
+        start: (&'m str, &'m str),
+        topic: (&str, &str),
+        max_words: usize,
+        rng: &mut R,
+    ) -> Vec<&'m str> {
+        let mut words = vec![start.0, start.1];
+        let mut state = start;
+
+        while words.len() < max_words {
+            let next = match self.choose_successor(state, topic, rng) {
+                Some(Some(next)) => next,
+                _ => break,
+            };
+
+            words.push(next);
+            state = (state.1, next);
+        }
+
+        words.truncate(max_words);
+        words
+    }
+
+    // A successor of `state` under `topic` drawn in proportion to how often
+    // it was seen, weighting by the approximate counts where the chain lists
+    // each successor once.
+    fn choose_successor<R: Rng + ?Sized>(
+        &self,
+        state: (&str, &str),
+        topic: (&str, &str),
+        rng: &mut R,
+    ) -> Option<Option<&str>> {
+        if self.approx.is_none() {
+            let (_, next) = self.observations(state, topic)?.choose(rng)?;
+            return Some(next.as_deref());
+        }
+
+        let (_, _, _, successors) = self
+            .approx_topics(state)?
+            .iter()
+            .find(|(t, ..)| t.0 == topic.0 && t.1 == topic.1)?;
+        let (next, ..) = successors.choose_weighted(rng, |s| s.1).ok()?;
+
+        Some(next.as_deref())
+    }
+}
+
+impl From<ChainMap> for Model {
+    fn from(chain: ChainMap) -> Self {
+        Model::with_metadata(chain, Metadata::default())
+    }
+}
+
+impl From<&Chain> for Model {
+    fn from(chain: &Chain) -> Self {
+        Model::with_metadata(chain.extract_map(), chain.extract_metadata())
+    }
+}
+
+// Size in a training chain of an entry with only the topics `policy` keeps.
+fn kept_entry_bytes(
+    policy: &dyn PrunePolicy,
+    state: &Bigram,
+    topic_map: &TopicMap,
+    counts: &ObservationCounts,
+) -> usize {
+    chain::owned_entry_bytes_where(state, topic_map, |topic, observations| {
+        prune::keep_topic(policy, counts, topic, observations)
+    })
+}
+
+fn vocabulary(chain: &ChainMap) -> HashSet<String> {
+    chain
+        .keys()
+        .flat_map(|(u1, u2)| iter::once(u1).chain(iter::once(u2)))
+        .cloned()
+        .collect()
+}
+
+fn get_by_str<'m, V, S: BuildHasher>(
+    map: &'m HashMap<(String, String), V, S>,
+    key: (&str, &str),
+) -> Option<&'m V> {
+    let mut hasher = map.hasher().build_hasher();
+    key.hash(&mut hasher);
+
+    map.raw_entry()
+        .from_hash(hasher.finish(), |k| k.0 == key.0 && k.1 == key.1)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prune::{All, SizeTarget, TopicThreshold};
+
+    fn bigram(word: &str) -> Bigram {
+        (word.to_string(), word.to_string())
+    }
+
+    // State `i` has `i` topics of one observation each.
+    fn model() -> Model {
+        let mut chain = ChainMap::new();
+        for i in 1..=4 {
+            let topic_map = (0..i)
+                .map(|t| (bigram(&t.to_string()), vec![(0, None)]))
+                .collect();
+
+            chain.insert(bigram(&format!("s{}", i)), topic_map);
+        }
+
+        // The single topic of `s1` was seen five times.
+        let mut metadata = Metadata::default();
+        let counts = Some((bigram("0"), 5)).into_iter().collect();
+        metadata.observation_counts.insert(bigram("s1"), counts);
+
+        Model::with_metadata(chain, metadata)
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut model = model();
+        let stats = model.prune(&Threshold::new(Metric::Topics, 3));
+
+        assert_eq!((stats.entries_before, stats.entries_after), (4, 2));
+        assert_eq!(stats.observations_dropped, 5 + 2);
+        assert!(model.topic_map(("s1", "s1")).is_none());
+        assert!(model.metadata().observation_counts.is_empty());
+        assert!(!model.contains_word("s2"));
+    }
+
+    #[test]
+    fn test_prune_to() {
+        let mut auto_threshold = AutoThreshold::new(Metric::Topics, SizeTarget::Entries(3), 1.0);
+        let stats = model().prune_to(
+            &Threshold::new(Metric::Topics, 0),
+            Some(&mut auto_threshold),
+            0,
+        );
+
+        assert_eq!(stats.entries_after, 3);
+        assert_eq!(auto_threshold.threshold(), 2);
+
+        let mut model = model();
+        let stats = model.prune_to(&Threshold::new(Metric::Topics, 10), None, 2);
+
+        assert_eq!(stats.entries_after, 2);
+        assert!(model.topic_map(("s4", "s4")).is_some());
+    }
+
+    #[test]
+    fn test_prune_to_min_entries() {
+        // The minimum keeps filtering topics: only `s1` has one seen twice.
+        let policy = All(vec![
+            Box::new(TopicThreshold { min: 2 }),
+            Box::new(Threshold::new(Metric::Topics, 10)),
+        ]);
+
+        let mut model = model();
+        let stats = model.prune_to(&policy, None, 1);
+
+        assert_eq!(stats.entries_after, 1);
+        assert!(model.topic_map(("s1", "s1")).is_some());
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let mut model = model();
+        let words = ["hi", ","];
+        assert_eq!(model.tokenizer(None).unwrap().detokenize(&words), "hi ,");
+        assert_eq!(model.tokenizer(None).unwrap().normalize("5 th"), "5 nth");
+
+        model.metadata.tokenizer = Some("punctuation".to_string());
+        assert_eq!(model.tokenizer(None).unwrap().detokenize(&words), "Hi,");
+
+        model.metadata.tokenizer = Some("nonsense".to_string());
+        assert!(model.tokenizer(None).is_err());
+    }
+
+    #[test]
+    fn test_tokenizer_rules() {
+        let mut model = model();
+        let rules = Rules::new("literal & => and").unwrap();
+        let other = Rules::new("literal & => n").unwrap();
+
+        assert!(model.tokenizer(Some(&rules)).is_err());
+
+        model.metadata.rules_hash = Some(rules.hash());
+        assert!(model.tokenizer(None).is_err());
+        assert!(model.tokenizer(Some(&other)).is_err());
+
+        // The given rules replace the default ones.
+        let tokenizer = model.tokenizer(Some(&rules)).unwrap();
+        assert_eq!(tokenizer.normalize("5 th & co"), "5 th and co");
+    }
+
+    #[test]
+    fn test_load() {
+        let model = model();
+
+        let mut file = Vec::new();
+        model.write(&mut file).unwrap();
+
+        let loaded = Model::from_reader(&file[..]).unwrap();
+        assert_eq!(loaded.num_entries(), 4);
+        assert_eq!(loaded.observation_count(("s1", "s1"), ("0", "0")), Some(5));
+
+        // Files written before metadata hold a bare chain map.
+        let bare = serde_pickle::to_vec(model.as_map(), true).unwrap();
+
+        let loaded = Model::from_reader(&bare[..]).unwrap();
+        assert_eq!(loaded.num_entries(), 4);
+        assert_eq!(loaded.observation_count(("s1", "s1"), ("0", "0")), Some(1));
+    }
+}
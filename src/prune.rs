@@ -0,0 +1,782 @@
+use crate::chain::{Bigram, ObservationCounts, TopicMap, Unigram};
+use crate::error::{Error, Result};
+
+use hashbrown::HashSet;
+use serde::Serialize;
+
+use std::{cell::Cell, cmp::max, collections::BTreeMap, fmt, str::FromStr};
+
+/// Statistics for a state bigram, after per-topic pruning.
+pub struct StateStats<'s> {
+    topics: usize,
+    observations: usize,
+
+    successors: Cell<Option<usize>>,
+    count_successors: &'s dyn Fn() -> usize,
+}
+
+impl<'s> StateStats<'s> {
+    pub fn new(
+        topics: usize,
+        observations: usize,
+        count_successors: &'s dyn Fn() -> usize,
+    ) -> Self {
+        StateStats {
+            topics,
+            observations,
+
+            successors: Cell::new(None),
+            count_successors,
+        }
+    }
+
+    /// Number of distinct topics the state was seen under.
+    pub fn topics(&self) -> usize {
+        self.topics
+    }
+
+    /// Total number of observations across all topics.
+    pub fn observations(&self) -> usize {
+        self.observations
+    }
+
+    /// Number of distinct successors across all topics. Computed on first use.
+    pub fn successors(&self) -> usize {
+        match self.successors.get() {
+            Some(successors) => successors,
+            None => {
+                let successors = (self.count_successors)();
+                self.successors.set(Some(successors));
+
+                successors
+            }
+        }
+    }
+}
+
+/// Statistics for a single topic of a state bigram.
+pub struct TopicStats {
+    pub observations: usize,
+}
+
+/// Decides which entries survive a prune. Topics are filtered first, then
+/// the state is judged on what remains; a state with no topics left is
+/// always dropped. Policies are shared with the chain, so they must be
+/// `Send` and `Sync`.
+pub trait PrunePolicy: Send + Sync {
+    fn keep_state(&self, state: &StateStats) -> bool;
+
+    fn keep_topic(&self, _topic: &TopicStats) -> bool {
+        true
+    }
+}
+
+/// Applies `policy` to an owned topic map, dropping the topics it rejects
+/// along with their `counts`. Returns whether the state itself should be
+/// kept.
+pub fn prune_topic_map(
+    topic_map: &mut TopicMap,
+    counts: &mut ObservationCounts,
+    policy: &dyn PrunePolicy,
+) -> bool {
+    topic_map.retain(|topic, observations| keep_topic(policy, counts, topic, observations));
+
+    counts.retain(|topic, _| topic_map.contains_key(topic));
+
+    !topic_map.is_empty() && with_stats(topic_map, counts, |stats| policy.keep_state(stats))
+}
+
+/// As `prune_topic_map`, recording what was dropped in `stats`. Entry counts
+/// are left to the caller.
+pub fn prune_recorded(
+    topic_map: &mut TopicMap,
+    counts: &mut ObservationCounts,
+    policy: &dyn PrunePolicy,
+    stats: &mut PruneStats,
+) -> bool {
+    let observations = |topic_map: &TopicMap, counts: &ObservationCounts| -> usize {
+        topic_map
+            .iter()
+            .map(|(topic, o)| counts.get(topic).copied().unwrap_or(o.len()))
+            .sum()
+    };
+
+    let (topics_before, observations_before) = (topic_map.len(), observations(topic_map, counts));
+
+    if prune_topic_map(topic_map, counts, policy) {
+        stats.observations_dropped += observations_before - observations(topic_map, counts);
+        true
+    } else {
+        stats.drop_state(topics_before, observations_before);
+        false
+    }
+}
+
+/// Whether `policy` keeps `topic`, taking its true observation count from
+/// `counts` if its list was sampled.
+pub fn keep_topic(
+    policy: &dyn PrunePolicy,
+    counts: &ObservationCounts,
+    topic: &Bigram,
+    observations: &[(i32, Option<Unigram>)],
+) -> bool {
+    policy.keep_topic(&TopicStats {
+        observations: counts.get(topic).copied().unwrap_or(observations.len()),
+    })
+}
+
+/// Calls `f` with the statistics of every topic in `topic_map`, taking true
+/// observation counts from `counts` where lists were sampled.
+pub fn with_stats<R, F: FnOnce(&StateStats) -> R>(
+    topic_map: &TopicMap,
+    counts: &ObservationCounts,
+    f: F,
+) -> R {
+    stats_where(topic_map, counts, |_, _| true, f)
+}
+
+/// As `with_stats`, over only the topics `policy` keeps, without changing
+/// `topic_map`. Returns `None` if it keeps none.
+pub fn with_kept_stats<R, F: FnOnce(&StateStats) -> R>(
+    topic_map: &TopicMap,
+    counts: &ObservationCounts,
+    policy: &dyn PrunePolicy,
+    f: F,
+) -> Option<R> {
+    stats_where(
+        topic_map,
+        counts,
+        |topic, observations| keep_topic(policy, counts, topic, observations),
+        |stats| match stats.topics() {
+            0 => None,
+            _ => Some(f(stats)),
+        },
+    )
+}
+
+fn stats_where<R, K, F>(topic_map: &TopicMap, counts: &ObservationCounts, keep: K, f: F) -> R
+where
+    K: Fn(&Bigram, &[(i32, Option<Unigram>)]) -> bool,
+    F: FnOnce(&StateStats) -> R,
+{
+    let kept = || {
+        topic_map
+            .iter()
+            .filter(|(topic, observations)| keep(topic, observations))
+    };
+
+    let (topics, observations) = kept().fold((0, 0), |(t, o), (topic, observations)| {
+        let seen = counts.get(topic).copied().unwrap_or(observations.len());
+        (t + 1, o + seen)
+    });
+
+    let count_successors = || {
+        kept()
+            .flat_map(|(_, observations)| observations.iter())
+            .map(|(_, successor)| successor.as_deref())
+            .collect::<HashSet<_>>()
+            .len()
+    };
+
+    f(&StateStats::new(topics, observations, &count_successors))
+}
+
+/// What a prune dropped.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PruneStats {
+    pub entries_before: usize,
+    pub entries_after: usize,
+    /// Observations of dropped states, and of dropped topics of kept ones.
+    pub observations_dropped: usize,
+    pub bytes_reclaimed: usize,
+    /// Number of dropped states by how many topics they had.
+    pub dropped_topics: BTreeMap<usize, usize>,
+}
+
+impl PruneStats {
+    pub fn entries_dropped(&self) -> usize {
+        self.entries_before.saturating_sub(self.entries_after)
+    }
+
+    /// What a sequence of prunes dropped in all.
+    pub fn total<'p, I: IntoIterator<Item = &'p PruneStats>>(prunes: I) -> PruneTotals {
+        let mut total = PruneTotals::default();
+        for prune in prunes {
+            total.entries_dropped += prune.entries_dropped();
+            total.observations_dropped += prune.observations_dropped;
+            total.bytes_reclaimed += prune.bytes_reclaimed;
+
+            for (&topics, &count) in prune.dropped_topics.iter() {
+                *total.dropped_topics.entry(topics).or_insert(0) += count;
+            }
+        }
+
+        total
+    }
+
+    pub(crate) fn drop_state(&mut self, topics: usize, observations: usize) {
+        *self.dropped_topics.entry(topics).or_insert(0) += 1;
+        self.observations_dropped += observations;
+    }
+}
+
+impl fmt::Display for PruneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} entries, {} observations and {:.3} GiB dropped",
+            self.entries_before,
+            self.entries_after,
+            self.observations_dropped,
+            self.bytes_reclaimed as f64 / bytesize::GIB as f64
+        )
+    }
+}
+
+/// What a sequence of prunes dropped, summed over the prunes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PruneTotals {
+    pub entries_dropped: usize,
+    pub observations_dropped: usize,
+    pub bytes_reclaimed: usize,
+    /// Number of dropped states by how many topics they had.
+    pub dropped_topics: BTreeMap<usize, usize>,
+}
+
+impl fmt::Display for PruneTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries, {} observations and {:.3} GiB dropped",
+            self.entries_dropped,
+            self.observations_dropped,
+            self.bytes_reclaimed as f64 / bytesize::GIB as f64
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Topics,
+    Observations,
+    Successors,
+}
+
+impl Metric {
+    pub fn of(&self, state: &StateStats) -> usize {
+        match self {
+            Metric::Topics => state.topics(),
+            Metric::Observations => state.observations(),
+            Metric::Successors => state.successors(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::Topics => "topics",
+            Metric::Observations => "observations",
+            Metric::Successors => "successors",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "topics" => Ok(Metric::Topics),
+            "observations" => Ok(Metric::Observations),
+            "successors" => Ok(Metric::Successors),
+            _ => Err(Error::InvalidPrunePolicy(format!(
+                "unknown metric '{}'",
+                name
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Keeps states whose `metric` is at least `min`.
+#[derive(Clone, Copy, Debug)]
+pub struct Threshold {
+    pub metric: Metric,
+    pub min: usize,
+}
+
+impl Threshold {
+    pub fn new(metric: Metric, min: usize) -> Self {
+        Threshold { metric, min }
+    }
+}
+
+impl PrunePolicy for Threshold {
+    fn keep_state(&self, state: &StateStats) -> bool {
+        self.metric.of(state) >= self.min
+    }
+}
+
+/// Keeps topics with at least `min` observations, and every state.
+#[derive(Clone, Copy, Debug)]
+pub struct TopicThreshold {
+    pub min: usize,
+}
+
+impl PrunePolicy for TopicThreshold {
+    fn keep_state(&self, _state: &StateStats) -> bool {
+        true
+    }
+
+    fn keep_topic(&self, topic: &TopicStats) -> bool {
+        topic.observations >= self.min
+    }
+}
+
+/// Keeps an entry only if every policy keeps it.
+pub struct All(pub Vec<Box<dyn PrunePolicy>>);
+
+impl PrunePolicy for All {
+    fn keep_state(&self, state: &StateStats) -> bool {
+        self.0.iter().all(|p| p.keep_state(state))
+    }
+
+    fn keep_topic(&self, topic: &TopicStats) -> bool {
+        self.0.iter().all(|p| p.keep_topic(topic))
+    }
+}
+
+/// Keeps a state if any policy keeps it, and a topic only if every policy
+/// keeps it, so that topic filters apply whichever policy keeps the state.
+///
+/// A policy that only filters topics keeps every state, so it makes the
+/// whole `Any` keep every state; `PolicySpec` rejects such alternatives.
+pub struct Any(pub Vec<Box<dyn PrunePolicy>>);
+
+impl PrunePolicy for Any {
+    fn keep_state(&self, state: &StateStats) -> bool {
+        self.0.iter().any(|p| p.keep_state(state))
+    }
+
+    fn keep_topic(&self, topic: &TopicStats) -> bool {
+        self.0.iter().all(|p| p.keep_topic(topic))
+    }
+}
+
+pub(crate) struct Both<'p>(pub &'p dyn PrunePolicy, pub &'p dyn PrunePolicy);
+
+impl<'p> PrunePolicy for Both<'p> {
+    fn keep_state(&self, state: &StateStats) -> bool {
+        self.0.keep_state(state) && self.1.keep_state(state)
+    }
+
+    fn keep_topic(&self, topic: &TopicStats) -> bool {
+        self.0.keep_topic(topic) && self.1.keep_topic(topic)
+    }
+}
+
+/// Keeps the topics the first policy keeps, and a state if either keeps it:
+/// a policy topped up to a minimum number of entries by a threshold on its
+/// metric.
+pub(crate) struct Floor<'p>(pub &'p dyn PrunePolicy, pub Threshold);
+
+impl<'p> Floor<'p> {
+    /// Keeps every state with a topic left after `policy`'s topic filter.
+    pub fn everything(policy: &'p dyn PrunePolicy, metric: Metric) -> Self {
+        Floor(policy, Threshold::new(metric, 0))
+    }
+}
+
+impl<'p> PrunePolicy for Floor<'p> {
+    fn keep_state(&self, state: &StateStats) -> bool {
+        self.0.keep_state(state) || self.1.keep_state(state)
+    }
+
+    fn keep_topic(&self, topic: &TopicStats) -> bool {
+        self.0.keep_topic(topic)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SizeTarget {
+    Entries(usize),
+    Bytes(usize),
+}
+
+/// Number of entries and their approximate size in bytes, by metric value.
+pub type Histogram = BTreeMap<usize, (usize, usize)>;
+
+/// Chooses a threshold on `metric` at each prune so that the final chain
+/// fits `target`, on top of the chain's own prune policy.
+///
+/// Intermediate prunes keep up to `headroom` times the target, as counts are
+/// still growing, and never lower the threshold. They also raise it until
+/// the survivors fit in half a pool, so a chain that fills its pools quickly
+/// prunes harder. The final prune uses the exact cutoff for `target`.
+#[derive(Clone, Debug)]
+pub struct AutoThreshold {
+    pub metric: Metric,
+    pub target: SizeTarget,
+    pub headroom: f64,
+
+    thresholds: Vec<usize>,
+}
+
+impl AutoThreshold {
+    pub fn new(metric: Metric, target: SizeTarget, headroom: f64) -> Self {
+        AutoThreshold {
+            metric,
+            target,
+            headroom,
+
+            thresholds: Vec::new(),
+        }
+    }
+
+    /// The threshold chosen by the most recent prune.
+    pub fn threshold(&self) -> usize {
+        self.thresholds.last().copied().unwrap_or(0)
+    }
+
+    /// Every threshold chosen so far, in order.
+    pub fn thresholds(&self) -> &[usize] {
+        &self.thresholds
+    }
+
+    pub(crate) fn update(&mut self, histogram: &Histogram, pool_bytes: Option<usize>) -> usize {
+        let (mut max_entries, mut max_bytes) = match self.target {
+            SizeTarget::Entries(entries) => (entries, usize::MAX),
+            SizeTarget::Bytes(bytes) => (usize::MAX, bytes),
+        };
+
+        let threshold = match pool_bytes {
+            Some(pool_bytes) => {
+                max_entries = scale(max_entries, self.headroom);
+                max_bytes = scale(max_bytes, self.headroom).min(pool_bytes / 2);
+
+                max(cutoff(histogram, max_entries, max_bytes), self.threshold())
+            }
+            None => cutoff(histogram, max_entries, max_bytes),
+        };
+
+        self.thresholds.push(threshold);
+        threshold
+    }
+}
+
+fn scale(limit: usize, factor: f64) -> usize {
+    match limit {
+        usize::MAX => usize::MAX,
+        limit => (limit as f64 * factor) as usize,
+    }
+}
+
+/// The smallest threshold for which the entries at or above it fit within
+/// both limits.
+pub(crate) fn cutoff(histogram: &Histogram, max_entries: usize, max_bytes: usize) -> usize {
+    let (mut entries, mut bytes) = (0usize, 0usize);
+    for (&value, &(e, b)) in histogram.iter().rev() {
+        if entries + e > max_entries || bytes + b > max_bytes {
+            return value + 1;
+        }
+
+        entries += e;
+        bytes += b;
+    }
+
+    0
+}
+
+/// The largest threshold for which at least `min_entries` entries are at or
+/// above it, or 0 if there are not that many.
+pub(crate) fn floor(histogram: &Histogram, min_entries: usize) -> usize {
+    let mut entries = 0;
+    for (&value, &(e, _)) in histogram.iter().rev() {
+        entries += e;
+        if entries >= min_entries {
+            return value;
+        }
+    }
+
+    0
+}
+
+/// How the prune at the end of training differs from those during it.
+#[derive(Default)]
+pub struct FinalPrune {
+    /// Replaces the training policy.
+    pub policy: Option<Box<dyn PrunePolicy>>,
+    /// Keeps every entry.
+    pub skip: bool,
+    /// When the policy keeps fewer entries than this, also keeps the highest
+    /// on the metric up to this many.
+    pub min_entries: usize,
+}
+
+/// A policy parsed from a string such as `topics:16,observations:100`.
+///
+/// Comma-separated terms must all hold, and `|` separates alternatives of
+/// which any may hold. Terms are `topics:N`, `observations:N`,
+/// `successors:N` and `topic-observations:N`, the last of which prunes
+/// individual topics rather than states. Since it keeps every state, it
+/// can't be combined with `|`.
+#[derive(Clone, Debug)]
+pub struct PolicySpec {
+    alternatives: Vec<Vec<Term>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Term {
+    State(Threshold),
+    Topic(TopicThreshold),
+}
+
+impl PolicySpec {
+    pub fn topics(min: usize) -> Self {
+        PolicySpec {
+            alternatives: vec![vec![Term::State(Threshold::new(Metric::Topics, min))]],
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn PrunePolicy> {
+        let mut alternatives: Vec<Box<dyn PrunePolicy>> = self
+            .alternatives
+            .iter()
+            .map(|terms| -> Box<dyn PrunePolicy> {
+                let mut terms: Vec<Box<dyn PrunePolicy>> = terms
+                    .iter()
+                    .map(|term| -> Box<dyn PrunePolicy> {
+                        match *term {
+                            Term::State(threshold) => Box::new(threshold),
+                            Term::Topic(threshold) => Box::new(threshold),
+                        }
+                    })
+                    .collect();
+
+                match terms.len() {
+                    1 => terms.pop().unwrap(),
+                    _ => Box::new(All(terms)),
+                }
+            })
+            .collect();
+
+        match alternatives.len() {
+            1 => alternatives.pop().unwrap(),
+            _ => Box::new(Any(alternatives)),
+        }
+    }
+}
+
+impl FromStr for PolicySpec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidPrunePolicy(format!("{}: {}", spec, reason));
+
+        let mut alternatives = Vec::new();
+        for alternative in spec.split('|') {
+            let mut terms = Vec::new();
+            for term in alternative.split(',').map(str::trim) {
+                let (name, min) = match term.split_once(':') {
+                    Some((name, min)) => (name.trim(), min.trim()),
+                    None => return Err(invalid("expected terms of the form name:N")),
+                };
+
+                let min = min
+                    .parse()
+                    .map_err(|_| invalid(&format!("invalid threshold '{}'", min)))?;
+
+                terms.push(match name {
+                    "topics" => Term::State(Threshold::new(Metric::Topics, min)),
+                    "observations" => Term::State(Threshold::new(Metric::Observations, min)),
+                    "successors" => Term::State(Threshold::new(Metric::Successors, min)),
+                    "topic-observations" => Term::Topic(TopicThreshold { min }),
+                    _ => return Err(invalid(&format!("unknown metric '{}'", name))),
+                });
+            }
+
+            alternatives.push(terms);
+        }
+
+        let filters_topics = alternatives
+            .iter()
+            .flatten()
+            .any(|term| matches!(term, Term::Topic(_)));
+
+        if filters_topics && alternatives.len() > 1 {
+            return Err(invalid(
+                "topic-observations keeps every state, so it can't be an alternative",
+            ));
+        }
+
+        Ok(PolicySpec { alternatives })
+    }
+}
+
+impl fmt::Display for PolicySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, terms) in self.alternatives.iter().enumerate() {
+            if i > 0 {
+                write!(f, "|")?;
+            }
+
+            for (j, term) in terms.iter().enumerate() {
+                if j > 0 {
+                    write!(f, ",")?;
+                }
+
+                match term {
+                    Term::State(t) => write!(f, "{}:{}", t.metric.name(), t.min)?,
+                    Term::Topic(t) => write!(f, "topic-observations:{}", t.min)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(topics: usize, observations: usize, successors: usize) -> bool {
+        let count_successors = move || successors;
+        let stats = StateStats::new(topics, observations, &count_successors);
+
+        "topics:4,observations:10|successors:8"
+            .parse::<PolicySpec>()
+            .unwrap()
+            .build()
+            .keep_state(&stats)
+    }
+
+    #[test]
+    fn test_spec_round_trip() {
+        for spec in &[
+            "topics:16",
+            "topics:4,observations:10|successors:8",
+            "topics:4,topic-observations:2",
+        ] {
+            assert_eq!(spec.parse::<PolicySpec>().unwrap().to_string(), *spec);
+        }
+    }
+
+    #[test]
+    fn test_spec_invalid() {
+        for spec in &[
+            "",
+            "topics",
+            "topics:x",
+            "words:3",
+            "topics:3,",
+            "topics:16|topic-observations:2",
+            "topics:4|successors:8,topic-observations:2",
+        ] {
+            assert!(spec.parse::<PolicySpec>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_spec_keep_state() {
+        assert!(stats(4, 10, 0));
+        assert!(stats(1, 1, 8));
+        assert!(!stats(4, 9, 7));
+        assert!(!stats(3, 100, 7));
+    }
+
+    #[test]
+    fn test_prune_recorded() {
+        let policy = "topics:2,topic-observations:2"
+            .parse::<PolicySpec>()
+            .unwrap()
+            .build();
+
+        let topic = |t: &str| (t.to_string(), t.to_string());
+        let observations = |n| vec![(0, None); n];
+
+        let mut stats = PruneStats::default();
+
+        let mut topic_map: TopicMap =
+            vec![(topic("a"), observations(3)), (topic("b"), observations(1))]
+                .into_iter()
+                .collect();
+        let mut counts: ObservationCounts = vec![(topic("a"), 5)].into_iter().collect();
+
+        assert!(!prune_recorded(
+            &mut topic_map,
+            &mut counts,
+            &*policy,
+            &mut stats
+        ));
+        assert_eq!(stats.observations_dropped, 6);
+        assert_eq!(stats.dropped_topics[&2], 1);
+
+        let mut topic_map: TopicMap = vec![
+            (topic("a"), observations(2)),
+            (topic("b"), observations(2)),
+            (topic("c"), observations(1)),
+        ]
+        .into_iter()
+        .collect();
+
+        assert!(prune_recorded(
+            &mut topic_map,
+            &mut ObservationCounts::new(),
+            &*policy,
+            &mut stats
+        ));
+        assert_eq!(stats.observations_dropped, 7);
+
+        let total = PruneStats::total(&[
+            PruneStats {
+                entries_before: 10,
+                entries_after: 4,
+                ..stats.clone()
+            },
+            PruneStats {
+                entries_before: 8,
+                entries_after: 3,
+                ..stats
+            },
+        ]);
+
+        assert_eq!(total.entries_dropped, 11);
+        assert_eq!(total.observations_dropped, 14);
+        assert_eq!(total.dropped_topics[&2], 2);
+    }
+
+    #[test]
+    fn test_auto_threshold() {
+        let histogram: Histogram = vec![(1, (100, 1000)), (2, (50, 500)), (5, (10, 100))]
+            .into_iter()
+            .collect();
+
+        let mut auto = AutoThreshold::new(Metric::Topics, SizeTarget::Entries(60), 2.0);
+        assert_eq!(auto.update(&histogram, Some(usize::MAX)), 2);
+        assert_eq!(auto.update(&histogram, None), 2);
+
+        let mut auto = AutoThreshold::new(Metric::Topics, SizeTarget::Bytes(99), 4.0);
+        assert_eq!(auto.update(&histogram, Some(usize::MAX)), 3);
+        assert_eq!(auto.update(&histogram, Some(0)), 6);
+        assert_eq!(auto.update(&histogram, None), 6);
+
+        assert_eq!(auto.thresholds(), &[3, 6, 6]);
+    }
+
+    #[test]
+    fn test_floor() {
+        let histogram: Histogram = vec![(1, (100, 1000)), (2, (50, 500)), (5, (10, 100))]
+            .into_iter()
+            .collect();
+
+        assert_eq!(floor(&histogram, 10), 5);
+        assert_eq!(floor(&histogram, 11), 2);
+        assert_eq!(floor(&histogram, 160), 1);
+        assert_eq!(floor(&histogram, 161), 0);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::chain::BUnigram;
+
+use hashbrown::HashMap;
+
+use std::slice;
+
+/// The observations recorded for a state bigram under a single topic, in
+/// insertion order. A successor of `None` marks the end of a line.
+#[derive(Clone, Copy)]
+pub struct Successors<'m> {
+    observations: Observations<'m>,
+}
+
+#[derive(Clone, Copy)]
+enum Observations<'m> {
+    Pooled(&'m [(i32, Option<BUnigram<'static>>)]),
+    Owned(&'m [(i32, Option<String>)]),
+}
+
+impl<'m> Successors<'m> {
+    pub(crate) fn pooled(observations: &'m [(i32, Option<BUnigram<'static>>)]) -> Self {
+        Successors {
+            observations: Observations::Pooled(observations),
+        }
+    }
+
+    pub(crate) fn owned(observations: &'m [(i32, Option<String>)]) -> Self {
+        Successors {
+            observations: Observations::Owned(observations),
+        }
+    }
+
+    /// Total number of observations.
+    pub fn len(&self) -> usize {
+        match self.observations {
+            Observations::Pooled(o) => o.len(),
+            Observations::Owned(o) => o.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every observation as `(sequence number, successor)`.
+    pub fn iter(&self) -> ObservationIter<'m> {
+        let inner = match self.observations {
+            Observations::Pooled(o) => ObservationIterInner::Pooled(o.iter()),
+            Observations::Owned(o) => ObservationIterInner::Owned(o.iter()),
+        };
+
+        ObservationIter { inner }
+    }
+
+    /// Number of times `successor` was observed.
+    pub fn count(&self, successor: Option<&str>) -> usize {
+        self.iter().filter(|(_, s)| *s == successor).count()
+    }
+
+    pub fn probability(&self, successor: Option<&str>) -> f64 {
+        match self.len() {
+            0 => 0.0,
+            len => self.count(successor) as f64 / len as f64,
+        }
+    }
+
+    /// Distinct successors with their counts, most frequent first.
+    pub fn counts(&self) -> impl Iterator<Item = (Option<&'m str>, usize)> {
+        let mut counts = HashMap::<Option<&'m str>, usize>::new();
+        for (_, successor) in self.iter() {
+            *counts.entry(successor).or_insert(0) += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        counts.into_iter()
+    }
+
+    /// Distinct successors with their empirical probabilities, most probable
+    /// first.
+    pub fn probabilities(&self) -> impl Iterator<Item = (Option<&'m str>, f64)> {
+        let len = self.len() as f64;
+        self.counts()
+            .map(move |(successor, count)| (successor, count as f64 / len))
+    }
+}
+
+impl<'m> IntoIterator for Successors<'m> {
+    type Item = (i32, Option<&'m str>);
+    type IntoIter = ObservationIter<'m>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct ObservationIter<'m> {
+    inner: ObservationIterInner<'m>,
+}
+
+enum ObservationIterInner<'m> {
+    Pooled(slice::Iter<'m, (i32, Option<BUnigram<'static>>)>),
+    Owned(slice::Iter<'m, (i32, Option<String>)>),
+}
+
+impl<'m> Iterator for ObservationIter<'m> {
+    type Item = (i32, Option<&'m str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            ObservationIterInner::Pooled(it) => {
+                it.next().map(|(n, s)| (*n, s.as_ref().map(|s| s.as_str())))
+            }
+            ObservationIterInner::Owned(it) => it.next().map(|(n, s)| (*n, s.as_deref())),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            ObservationIterInner::Pooled(it) => it.size_hint(),
+            ObservationIterInner::Owned(it) => it.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observations() -> Vec<(i32, Option<String>)> {
+        vec![
+            (0, Some("fox".into())),
+            (1, Some("dog".into())),
+            (0, Some("fox".into())),
+            (2, None),
+        ]
+    }
+
+    #[test]
+    fn test_counts() {
+        let observations = observations();
+        let successors = Successors::owned(&observations);
+
+        assert_eq!(successors.len(), 4);
+        assert_eq!(successors.count(Some("fox")), 2);
+        assert_eq!(successors.count(Some("cat")), 0);
+
+        let counts: Vec<_> = successors.counts().collect();
+        assert_eq!(counts, vec![(Some("fox"), 2), (None, 1), (Some("dog"), 1)]);
+    }
+
+    #[test]
+    fn test_probabilities() {
+        let observations = observations();
+        let successors = Successors::owned(&observations);
+
+        assert_eq!(successors.probability(Some("fox")), 0.5);
+        assert_eq!(successors.probability(None), 0.25);
+
+        let total: f64 = successors.probabilities().map(|(_, p)| p).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+}
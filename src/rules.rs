@@ -0,0 +1,343 @@
+//! User-defined normalization rules, applied on top of a tokenizer.
+//!
+//! A rules file has one rule per line. Blank lines and lines starting with
+//! `#` are ignored. Line rules rewrite the raw line in file order, before
+//! the tokenizer normalises it, so they see its case and punctuation.
+//! Word rules then apply to each word in file order: a map rewrites the word
+//! as it is at that point, so later maps and drops see the rewritten word,
+//! and a drop removes it, skipping the rules after it.
+//!
+//! ```text
+//! # Rewrites the raw line.
+//! regex   \bth\b => nth
+//! literal " & " => " and "
+//! # Rewrites whole words after splitting.
+//! map     colour => color
+//! # Drops words matching a pattern.
+//! drop    ^\d+$
+//! ```
+//!
+//! Values may be wrapped in double quotes to keep surrounding whitespace.
+//! Regex replacements may refer to groups as `$1`.
+//!
+//! The default tokenizer comes with `DEFAULT_RULES`. A rules file replaces
+//! them, so copy them into it to keep them.
+
+use crate::error::{Error, Result};
+use crate::tokenizer::Tokenizer;
+
+use hashbrown::HashMap;
+use regex::Regex;
+
+use std::path::Path;
+
+/// The rules the default tokenizer applies unless a rules file replaces
+/// them.
+pub const DEFAULT_RULES: &str = r"
+    # A lone th, as in 5 th, reads as nth.
+    regex (?i)\bth\b => nth
+";
+
+#[derive(Clone)]
+enum Rule {
+    Regex(Regex, String),
+    Literal(String, String),
+    Map(String, String),
+    Drop(Regex),
+}
+
+impl Rule {
+    // The kind and values, as in the rules file.
+    fn parts(&self) -> [&str; 3] {
+        match self {
+            Rule::Regex(re, to) => ["regex", re.as_str(), to],
+            Rule::Literal(from, to) => ["literal", from, to],
+            Rule::Map(from, to) => ["map", from, to],
+            Rule::Drop(re) => ["drop", re.as_str(), ""],
+        }
+    }
+}
+
+// Word rules as applied: each run of consecutive maps is folded into one
+// table from a word to what the run leaves of it.
+#[derive(Clone)]
+enum WordRule {
+    Map(HashMap<String, String>),
+    Drop(Regex),
+}
+
+/// An ordered list of rules.
+#[derive(Clone)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    word_rules: Vec<WordRule>,
+}
+
+impl Rules {
+    pub fn new(rules: &str) -> Result<Self> {
+        let mut parsed = Vec::new();
+
+        for (i, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason: &str| Error::InvalidRule(format!("line {}: {}", i + 1, reason));
+            let regex = |pattern: &str| Regex::new(pattern).map_err(|e| invalid(&e.to_string()));
+
+            let (kind, rest) = line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()));
+            let replacement = || match rest.split_once(" => ") {
+                Some((from, to)) => Ok((unquote(from), unquote(to))),
+                None => Err(invalid("expected `from => to`")),
+            };
+
+            parsed.push(match kind {
+                "regex" => {
+                    let (from, to) = replacement()?;
+                    Rule::Regex(regex(from)?, to.to_string())
+                }
+                "literal" => {
+                    let (from, to) = replacement()?;
+                    Rule::Literal(from.to_string(), to.to_string())
+                }
+                "map" => {
+                    let (from, to) = replacement()?;
+                    Rule::Map(from.to_string(), to.to_string())
+                }
+                "drop" => Rule::Drop(regex(unquote(rest))?),
+                _ => return Err(invalid(&format!("unknown rule `{}`", kind))),
+            });
+        }
+
+        Ok(Self::from_rules(parsed))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(&std::fs::read_to_string(path)?)
+    }
+
+    fn from_rules(rules: Vec<Rule>) -> Self {
+        let mut word_rules = Vec::new();
+        let mut run: Vec<(&str, &str)> = Vec::new();
+
+        for rule in &rules {
+            match rule {
+                Rule::Map(from, to) => run.push((from, to)),
+                Rule::Drop(re) => {
+                    word_rules.extend(fold_maps(&mut run));
+                    word_rules.push(WordRule::Drop(re.clone()));
+                }
+                _ => {}
+            }
+        }
+        word_rules.extend(fold_maps(&mut run));
+
+        Rules { rules, word_rules }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies the line replacements in order.
+    pub fn rewrite_line(&self, line: String) -> String {
+        self.rules.iter().fold(line, |line, rule| match rule {
+            Rule::Regex(re, to) => re.replace_all(&line, to.as_str()).into_owned(),
+            Rule::Literal(from, to) => line.replace(from.as_str(), to),
+            _ => line,
+        })
+    }
+
+    /// Applies the word rules to `word` in order. Returns what it becomes,
+    /// or `None` if it is dropped.
+    pub fn rewrite_word<'a>(&'a self, word: &'a str) -> Option<&'a str> {
+        let mut word = word;
+        for rule in &self.word_rules {
+            match rule {
+                WordRule::Map(mappings) => {
+                    if let Some(to) = mappings.get(word) {
+                        word = to;
+                    }
+                }
+                WordRule::Drop(re) if re.is_match(word) => return None,
+                WordRule::Drop(_) => {}
+            }
+        }
+
+        Some(word)
+    }
+
+    /// A hash of the rules themselves, ignoring comments and formatting, as
+    /// 16 hex digits. It is the same across runs and platforms.
+    pub fn hash(&self) -> String {
+        // 64-bit FNV-1a over each value's length and raw bytes.
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for rule in &self.rules {
+            for part in rule.parts().iter() {
+                let len = (part.len() as u64).to_le_bytes();
+                for &byte in len.iter().chain(part.as_bytes()) {
+                    hash ^= byte as u64;
+                    hash = hash.wrapping_mul(0x0100_0000_01b3);
+                }
+            }
+        }
+
+        format!("{:016x}", hash)
+    }
+}
+
+// Folds a run of maps into the table of what applying them in order does
+// to each word, emptying `run`.
+fn fold_maps(run: &mut Vec<(&str, &str)>) -> Option<WordRule> {
+    if run.is_empty() {
+        return None;
+    }
+
+    let mappings = run
+        .iter()
+        .map(|&(from, _)| {
+            let to = run
+                .iter()
+                .fold(from, |word, &(f, t)| if word == f { t } else { word });
+            (from.to_string(), to.to_string())
+        })
+        .collect();
+
+    run.clear();
+    Some(WordRule::Map(mappings))
+}
+
+// Strips surrounding whitespace, then one pair of double quotes.
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    match value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        true => &value[1..value.len() - 1],
+        false => value,
+    }
+}
+
+/// A tokenizer with line rules applied before it normalises each line, and
+/// word rules after it splits it.
+pub struct WithRules {
+    tokenizer: Box<dyn Tokenizer>,
+    rules: Rules,
+}
+
+impl WithRules {
+    pub fn new(tokenizer: Box<dyn Tokenizer>, rules: Rules) -> Self {
+        WithRules { tokenizer, rules }
+    }
+
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+}
+
+impl Tokenizer for WithRules {
+    fn normalize(&self, line: &str) -> String {
+        self.tokenizer
+            .normalize(&self.rules.rewrite_line(line.to_string()))
+    }
+
+    /// Splits with the tokenizer, then applies the word rules, dropping
+    /// the words they drop.
+    fn split<'a>(&'a self, line: &'a str) -> Vec<&'a str> {
+        self.tokenizer
+            .split(line)
+            .into_iter()
+            .filter_map(|word| self.rules.rewrite_word(word))
+            .collect()
+    }
+
+    fn filter(&self, word: &str) -> bool {
+        self.tokenizer.filter(word)
+    }
+
+    fn detokenize(&self, words: &[&str]) -> String {
+        self.tokenizer.detokenize(words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{LineProcessor, WhitespaceTokenizer};
+
+    const RULES: &str = r#"
+        # Ordinals.
+        regex \b(\d+) th\b => ${1}th
+        literal " & " => " and "
+
+        map colour => color
+        map colour => colr
+        drop ^\d+$
+    "#;
+
+    #[test]
+    fn test_rules() {
+        let tokenizer = WithRules::new(Box::new(WhitespaceTokenizer), Rules::new(RULES).unwrap());
+        let line = tokenizer.normalize("The 5 th Colour & 42 shades");
+
+        assert_eq!(line, "the 5th colour and 42 shades");
+        assert_eq!(
+            tokenizer.tokenize(&line),
+            vec!["the", "5th", "color", "and", "shades"]
+        );
+    }
+
+    #[test]
+    fn test_rules_before_normalizing() {
+        // The default tokenizer strips punctuation, so `&` is only there to
+        // match before it runs.
+        let rules = Rules::new(r#"literal " & " => " and ""#).unwrap();
+        let tokenizer = WithRules::new(Box::new(LineProcessor::default()), rules);
+
+        assert_eq!(tokenizer.normalize("Salt & pepper!"), "salt and pepper");
+    }
+
+    #[test]
+    fn test_word_rule_order() {
+        let rules = Rules::new(
+            "map a => b\nmap b => c\ndrop ^c$\nmap x => y\ndrop ^y$\nmap y => z\nmap q => x",
+        )
+        .unwrap();
+
+        // Maps chain forward, and a drop sees what the maps before it left.
+        assert_eq!(rules.rewrite_word("a"), None);
+        assert_eq!(rules.rewrite_word("b"), None);
+        assert_eq!(rules.rewrite_word("x"), None);
+
+        // A map only applies to what the rules before it left.
+        assert_eq!(rules.rewrite_word("q"), Some("x"));
+        assert_eq!(rules.rewrite_word("y"), None);
+        assert_eq!(rules.rewrite_word("w"), Some("w"));
+    }
+
+    #[test]
+    fn test_hash() {
+        let rules = Rules::new(RULES).unwrap();
+        let reformatted = Rules::new(&RULES.replace("# Ordinals.", "").replace("map ", "map   "));
+
+        assert_eq!(rules.hash(), reformatted.unwrap().hash());
+        assert_ne!(rules.hash(), Rules::new("drop x").unwrap().hash());
+
+        // Values are hashed raw, so no two splits of the same text collide.
+        assert_ne!(
+            Rules::new("map a => b c").unwrap().hash(),
+            Rules::new("map a b => c").unwrap().hash()
+        );
+        assert_eq!(Rules::new("").unwrap().hash(), "cbf29ce484222325");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Rules::new("regex ( => x").is_err());
+        assert!(Rules::new("map colour").is_err());
+        assert!(Rules::new("swap a => b").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use rand::{seq::SliceRandom, Rng};
+
+/// Where the `seen`th observation (counting from zero) goes in a full
+/// reservoir of `capacity`, or `None` if it is dropped. Keeps every
+/// observation with equal probability.
+pub fn reservoir_slot<R: Rng + ?Sized>(seen: usize, capacity: usize, rng: &mut R) -> Option<usize> {
+    let slot = rng.gen_range(0..=seen);
+    match slot < capacity {
+        true => Some(slot),
+        false => None,
+    }
+}
+
+/// Combines uniform samples of two disjoint populations, of `a_seen` and
+/// `b_seen` observations, into a uniform sample of at most `capacity` from
+/// their union.
+pub fn merge_samples<T, R: Rng + ?Sized>(
+    mut a: Vec<T>,
+    a_seen: usize,
+    mut b: Vec<T>,
+    b_seen: usize,
+    capacity: usize,
+    rng: &mut R,
+) -> Vec<T> {
+    if a.len() + b.len() <= capacity {
+        a.append(&mut b);
+        return a;
+    }
+
+    // How many of the combined sample come from each side follows the
+    // hypergeometric distribution over the populations.
+    let (mut a_left, mut b_left) = (a_seen, b_seen);
+    let mut a_take = 0;
+
+    for _ in 0..capacity {
+        if rng.gen_range(0..a_left + b_left) < a_left {
+            a_left -= 1;
+            a_take += 1;
+        } else {
+            b_left -= 1;
+        }
+    }
+
+    a.shuffle(rng);
+    b.shuffle(rng);
+
+    a.truncate(a_take);
+    b.truncate(capacity - a_take);
+
+    a.append(&mut b);
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_merge_samples() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let merged = merge_samples(vec![1, 2], 2, vec![3], 1, 4, &mut rng);
+        assert_eq!(merged, vec![1, 2, 3]);
+
+        let mut from_a = 0;
+        for _ in 0..1000 {
+            let merged = merge_samples(vec![0; 10], 900, vec![1; 10], 100, 10, &mut rng);
+
+            assert_eq!(merged.len(), 10);
+            from_a += merged.iter().filter(|&&x| x == 0).count();
+        }
+
+        // 90% expected from the larger population.
+        assert!((8500..9500).contains(&from_a));
+    }
+}
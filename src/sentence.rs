@@ -0,0 +1,213 @@
+use hashbrown::HashSet;
+
+/// Abbreviations that end in a full stop without ending a sentence, in
+/// lowercase and without their final stop.
+const ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "mt", "rev", "gen", "col", "lt", "sgt",
+    "capt", "gov", "sen", "rep", "hon", "vs", "etc", "e.g", "i.e", "cf", "al", "approx", "ca",
+    "inc", "ltd", "co", "corp", "dept", "univ", "no", "vol", "fig", "p", "pp", "ch", "ed", "eds",
+    "jan", "feb", "mar", "apr", "jun", "jul", "aug", "sep", "sept", "oct", "nov", "dec", "u.s",
+    "u.k", "a.m", "p.m",
+];
+
+/// Splits raw text into sentences at `.`, `!` and `?`, and at the CJK
+/// terminators `。`, `！` and `？`.
+///
+/// A Latin stop only ends a sentence when it is followed by whitespace and
+/// then something that can start one: an uppercase letter, a digit, an
+/// opening quote or bracket, or the end of the text. Stops after a known
+/// abbreviation or a single-letter initial never do. Without
+/// `set_require_capital`, whitespace after a stop is enough, for text that
+/// is not capitalised. CJK terminators end a sentence wherever they are,
+/// since CJK text has no spaces or case.
+pub struct SentenceSplitter {
+    abbreviations: HashSet<String>,
+    require_capital: bool,
+}
+
+impl SentenceSplitter {
+    pub fn new() -> Self {
+        SentenceSplitter {
+            abbreviations: ABBREVIATIONS.iter().map(|a| a.to_string()).collect(),
+            require_capital: true,
+        }
+    }
+
+    /// Sets whether a Latin stop needs a capital, digit, quote or bracket
+    /// after it to end a sentence. On by default.
+    pub fn set_require_capital(&mut self, require_capital: bool) {
+        self.require_capital = require_capital;
+    }
+
+    /// Adds abbreviations, which may be given with or without a final stop.
+    pub fn add_abbreviations<'a, I: IntoIterator<Item = &'a str>>(&mut self, abbreviations: I) {
+        for abbreviation in abbreviations {
+            let abbreviation = abbreviation.trim().trim_end_matches('.').to_lowercase();
+            if !abbreviation.is_empty() {
+                self.abbreviations.insert(abbreviation);
+            }
+        }
+    }
+
+    /// Sentences of `text`, with surrounding whitespace trimmed and empty
+    /// ones left out.
+    pub fn split<'t>(&self, text: &'t str) -> Vec<&'t str> {
+        let mut sentences = Vec::new();
+        let mut start = 0;
+
+        let mut chars = text.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if !is_terminator(c) {
+                continue;
+            }
+
+            // Runs of terminators and closing quotes or brackets.
+            let mut end = i + c.len_utf8();
+            let mut cjk = is_cjk_terminator(c);
+            while let Some(&(j, c)) = chars.peek() {
+                match c {
+                    '"' | '\'' | ')' | ']' | '”' | '’' | '»' | '」' | '』' | '）' => {}
+                    c if is_terminator(c) => cjk |= is_cjk_terminator(c),
+                    _ => break,
+                }
+
+                end = j + c.len_utf8();
+                chars.next();
+            }
+
+            if c == '.' && end == i + 1 && self.is_abbreviation(&text[start..i]) {
+                continue;
+            }
+
+            let rest = &text[end..];
+            let next = rest.trim_start().chars().next();
+
+            let ends_sentence = match next {
+                None => true,
+                Some(_) if cjk => true,
+                Some(_) if !rest.starts_with(char::is_whitespace) => false,
+                Some(_) if !self.require_capital => true,
+                Some(next) => {
+                    next.is_uppercase()
+                        || next.is_numeric()
+                        || matches!(next, '"' | '\'' | '(' | '[' | '“' | '‘' | '«')
+                }
+            };
+
+            if ends_sentence {
+                push_trimmed(&mut sentences, &text[start..end]);
+                start = end;
+            }
+        }
+
+        push_trimmed(&mut sentences, &text[start..]);
+        sentences
+    }
+
+    // Whether the word ending `before` is an abbreviation or an initial.
+    fn is_abbreviation(&self, before: &str) -> bool {
+        let word = before
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or("")
+            .trim_start_matches(|c: char| !c.is_alphanumeric());
+
+        let mut chars = word.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => c.is_alphabetic(),
+            (None, _) => false,
+            _ => self.abbreviations.contains(&word.to_lowercase()),
+        }
+    }
+}
+
+impl Default for SentenceSplitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_terminator(c: char) -> bool {
+    matches!(c, '.' | '!' | '?') || is_cjk_terminator(c)
+}
+
+fn is_cjk_terminator(c: char) -> bool {
+    matches!(c, '。' | '！' | '？')
+}
+
+fn push_trimmed<'t>(sentences: &mut Vec<&'t str>, sentence: &'t str) {
+    let sentence = sentence.trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let splitter = SentenceSplitter::new();
+        let text = "Mr. Smith met Dr. J. R. Jones at 3.30 p.m. yesterday. \
+                    \"Really?!\" she asked. (It was.) Prices rose, e.g. for tea... \
+                    2021 was worse. The end";
+
+        assert_eq!(
+            splitter.split(text),
+            vec![
+                "Mr. Smith met Dr. J. R. Jones at 3.30 p.m. yesterday.",
+                "\"Really?!\" she asked.",
+                "(It was.)",
+                "Prices rose, e.g. for tea...",
+                "2021 was worse.",
+                "The end",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cjk() {
+        let splitter = SentenceSplitter::new();
+        let text = "今日は晴れです。明日は？「雨だ！」と彼は言った。Then English.";
+
+        assert_eq!(
+            splitter.split(text),
+            vec![
+                "今日は晴れです。",
+                "明日は？",
+                "「雨だ！」",
+                "と彼は言った。",
+                "Then English.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_require_capital() {
+        let mut splitter = SentenceSplitter::new();
+        let text = "it rained. then it stopped. see dr. who.";
+
+        assert_eq!(splitter.split(text).len(), 1);
+
+        splitter.set_require_capital(false);
+        assert_eq!(
+            splitter.split(text),
+            vec!["it rained.", "then it stopped.", "see dr. who."]
+        );
+    }
+
+    #[test]
+    fn test_abbreviations() {
+        let mut splitter = SentenceSplitter::new();
+        let text = "See Approx. Ten of them. Or Abbr. Twenty.";
+
+        assert_eq!(splitter.split(text).len(), 3);
+
+        splitter.add_abbreviations(vec!["abbr."]);
+        assert_eq!(
+            splitter.split(text),
+            vec!["See Approx. Ten of them.", "Or Abbr. Twenty."]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use crate::unigram;
+
+use std::{
+    cmp::max,
+    hash::{BuildHasher, Hasher},
+    mem::size_of,
+};
+
+/// A count-min sketch over bigrams. Estimates never undercount.
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    counters: Vec<u32>,
+    hasher: ahash::RandomState,
+}
+
+impl CountMinSketch {
+    /// A sketch using about `bytes` bytes, with `depth` rows.
+    pub fn new(bytes: usize, depth: usize) -> Self {
+        let depth = max(depth, 1);
+        let width = max(bytes / size_of::<u32>() / depth, 1);
+
+        CountMinSketch {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+            hasher: ahash::RandomState::new(),
+        }
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.counters.len() * size_of::<u32>()
+    }
+
+    fn hash(&self, bigram: (&str, &str)) -> (u64, u64) {
+        let mut hasher = self.hasher.build_hasher();
+        unigram::hash_str(bigram.0, &mut hasher);
+        unigram::hash_str(bigram.1, &mut hasher);
+
+        let h1 = hasher.finish();
+        (h1, h1.rotate_left(32) | 1)
+    }
+
+    // The counter for `row`, by double hashing.
+    fn index(&self, row: usize, (h1, h2): (u64, u64)) -> usize {
+        let h = h1.wrapping_add((row as u64).wrapping_mul(h2));
+        row * self.width + (h % self.width as u64) as usize
+    }
+
+    fn estimate_hashed(&self, hash: (u64, u64)) -> u32 {
+        (0..self.depth)
+            .map(|row| self.counters[self.index(row, hash)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Counts one occurrence, only raising the counters that hold the
+    /// current estimate (conservative update).
+    pub fn add(&mut self, bigram: (&str, &str)) {
+        let hash = self.hash(bigram);
+        let estimate = self.estimate_hashed(hash).saturating_add(1);
+
+        for row in 0..self.depth {
+            let i = self.index(row, hash);
+            self.counters[i] = max(self.counters[i], estimate);
+        }
+    }
+
+    pub fn estimate(&self, bigram: (&str, &str)) -> u32 {
+        self.estimate_hashed(self.hash(bigram))
+    }
+}
+
+/// Filters state bigrams in a second training pass by how often they were
+/// seen in a first one. Since the prune policy judges states on counts that
+/// are at most their number of observations, a `min_count` no greater than
+/// the policy's thresholds only drops states that would be pruned anyway.
+pub struct Prefilter {
+    sketch: CountMinSketch,
+    min_count: u32,
+
+    skipped_observations: usize,
+    skipped_bytes: usize,
+}
+
+impl Prefilter {
+    pub fn new(min_count: u32, sketch_bytes: usize) -> Self {
+        Prefilter {
+            sketch: CountMinSketch::new(sketch_bytes, 4),
+            min_count,
+
+            skipped_observations: 0,
+            skipped_bytes: 0,
+        }
+    }
+
+    /// Counts the state bigrams of a tokenized line, in the first pass.
+    pub fn observe(&mut self, words: &[&str]) {
+        for pair in words.windows(2) {
+            self.sketch.add((pair[0], pair[1]));
+        }
+    }
+
+    pub fn admits(&self, state: (&str, &str)) -> bool {
+        self.sketch.estimate(state) >= self.min_count
+    }
+
+    pub(crate) fn skip(&mut self, bytes: usize) {
+        self.skipped_observations += 1;
+        self.skipped_bytes += bytes;
+    }
+
+    pub fn sketch(&self) -> &CountMinSketch {
+        &self.sketch
+    }
+
+    /// Observations not inserted in the second pass.
+    pub fn skipped_observations(&self) -> usize {
+        self.skipped_observations
+    }
+
+    /// Approximate pool bytes those observations would have used.
+    pub fn skipped_bytes(&self) -> usize {
+        self.skipped_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_never_undercounts() {
+        let mut sketch = CountMinSketch::new(256, 4);
+
+        let words: Vec<String> = (0..200).map(|i| format!("word{}", i)).collect();
+        for (i, word) in words.iter().enumerate() {
+            for _ in 0..(i % 5) {
+                sketch.add((word, "next"));
+            }
+        }
+
+        for (i, word) in words.iter().enumerate() {
+            assert!(sketch.estimate((word, "next")) >= (i % 5) as u32);
+        }
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let mut prefilter = Prefilter::new(2, 1 << 16);
+
+        prefilter.observe(&["a", "b", "c", "a", "b"]);
+
+        assert!(prefilter.admits(("a", "b")));
+        assert!(!prefilter.admits(("c", "a")));
+    }
+}
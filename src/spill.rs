@@ -0,0 +1,434 @@
+use crate::chain::{Bigram, ObservationCounts, TopicMap, Unigram};
+use crate::error::Result;
+use crate::sample;
+
+use rand::rngs::StdRng;
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+const NONE_LEN: u32 = u32::MAX;
+
+type Observation = (i32, Option<Unigram>);
+type Topics = Vec<(Bigram, usize, Vec<Observation>)>;
+
+/// Sorted run files written when the chain runs out of memory in exact mode.
+/// The files are removed when this is dropped.
+pub struct Runs {
+    dir: PathBuf,
+    paths: Vec<PathBuf>,
+    created: usize,
+}
+
+impl Runs {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Runs {
+            dir: dir.as_ref().to_path_buf(),
+            paths: Vec::new(),
+            created: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Starts a new run. States must be written in ascending order.
+    pub fn create(&mut self) -> Result<RunWriter> {
+        let path = self
+            .dir
+            .join(format!("run-{}-{}.bin", std::process::id(), self.created));
+
+        let writer = BufWriter::new(File::create(&path)?);
+        self.paths.push(path);
+        self.created += 1;
+
+        Ok(RunWriter { writer })
+    }
+
+    /// Merges every run, yielding each state once with the observations of
+    /// all runs combined, in ascending order of state. Lists sampled down in
+    /// training are merged into samples of at most `sample_size`, drawn with
+    /// `rng`.
+    pub fn merge(&self, sample_size: Option<usize>, rng: StdRng) -> Result<Merge> {
+        let mut merge = Merge {
+            readers: Vec::with_capacity(self.paths.len()),
+            pending: Vec::with_capacity(self.paths.len()),
+            heads: BinaryHeap::with_capacity(self.paths.len()),
+
+            sample_size,
+            rng,
+        };
+
+        for (i, path) in self.paths.iter().enumerate() {
+            merge.readers.push(RunReader {
+                reader: BufReader::new(File::open(path)?),
+            });
+
+            merge.pending.push(Vec::new());
+            merge.advance(i)?;
+        }
+
+        Ok(merge)
+    }
+
+    /// Merges every run into a single new one, as `merge` would, and removes
+    /// the others. `f` is called with each merged state as it is written.
+    pub fn compact<F>(&mut self, sample_size: Option<usize>, rng: StdRng, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Bigram, &TopicMap, &ObservationCounts),
+    {
+        let merge = self.merge(sample_size, rng)?;
+        let old = self.paths.len();
+
+        let mut run = self.create()?;
+        for entry in merge {
+            let (state, topic_map, counts) = entry?;
+
+            f(&state, &topic_map, &counts);
+            run.write_entry(&state, &topic_map, &counts)?;
+        }
+
+        run.finish()?;
+
+        for path in self.paths.drain(..old) {
+            let _ = fs::remove_file(path);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub struct RunWriter {
+    writer: BufWriter<File>,
+}
+
+impl RunWriter {
+    pub fn write_state(&mut self, state: (&str, &str), num_topics: usize) -> Result<()> {
+        self.write_bigram(state)?;
+        self.write_u64(num_topics as u64)
+    }
+
+    /// `seen` is the true number of observations, of which
+    /// `num_observations` follow.
+    pub fn write_topic(
+        &mut self,
+        topic: (&str, &str),
+        num_observations: usize,
+        seen: usize,
+    ) -> Result<()> {
+        self.write_bigram(topic)?;
+        self.write_u64(num_observations as u64)?;
+        self.write_u64(seen as u64)
+    }
+
+    pub fn write_observation(&mut self, seq_num: i32, successor: Option<&str>) -> Result<()> {
+        self.writer.write_all(&seq_num.to_le_bytes())?;
+        match successor {
+            Some(successor) => self.write_str(successor),
+            None => self.write_u32(NONE_LEN),
+        }
+    }
+
+    /// Writes a merged state, with the true counts of its sampled lists.
+    pub fn write_entry(
+        &mut self,
+        state: &Bigram,
+        topic_map: &TopicMap,
+        counts: &ObservationCounts,
+    ) -> Result<()> {
+        self.write_state((&state.0, &state.1), topic_map.len())?;
+
+        for (topic, observations) in topic_map.iter() {
+            let seen = counts.get(topic).copied().unwrap_or(observations.len());
+            self.write_topic((&topic.0, &topic.1), observations.len(), seen)?;
+
+            for (seq_num, successor) in observations.iter() {
+                self.write_observation(*seq_num, successor.as_deref())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write_bigram(&mut self, bigram: (&str, &str)) -> Result<()> {
+        self.write_str(bigram.0)?;
+        self.write_str(bigram.1)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<()> {
+        let len = match u32::try_from(s.len()) {
+            Ok(len) if len != NONE_LEN => len,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "word too long").into()),
+        };
+
+        self.write_u32(len)?;
+        self.writer.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    fn write_u32(&mut self, n: u32) -> Result<()> {
+        self.writer.write_all(&n.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_u64(&mut self, n: u64) -> Result<()> {
+        self.writer.write_all(&n.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+}
+
+impl RunReader {
+    fn next_state(&mut self) -> Result<Option<(Bigram, Topics)>> {
+        let len = match self.read_u32() {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let state = (self.read_string(len)?, self.read_unigram()?);
+
+        let num_topics = self.read_count()?;
+        let mut topics = Vec::with_capacity(num_topics);
+
+        for _ in 0..num_topics {
+            let topic = (self.read_unigram()?, self.read_unigram()?);
+
+            let num_observations = self.read_count()?;
+            let seen = self.read_count()?;
+            let mut observations = Vec::with_capacity(num_observations);
+
+            for _ in 0..num_observations {
+                let mut seq_num = [0u8; 4];
+                self.reader.read_exact(&mut seq_num)?;
+
+                let successor = match self.read_u32()? {
+                    NONE_LEN => None,
+                    len => Some(self.read_string(len)?),
+                };
+
+                observations.push((i32::from_le_bytes(seq_num), successor));
+            }
+
+            topics.push((topic, seen, observations));
+        }
+
+        Ok(Some((state, topics)))
+    }
+
+    fn read_unigram(&mut self) -> Result<Unigram> {
+        let len = self.read_u32()?;
+        self.read_string(len)
+    }
+
+    fn read_string(&mut self, len: u32) -> Result<Unigram> {
+        let mut buf = vec![0u8; len as usize];
+        self.reader.read_exact(&mut buf)?;
+
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.reader.read_exact(&mut buf)?;
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_count(&mut self) -> Result<usize> {
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf)?;
+
+        usize::try_from(u64::from_le_bytes(buf))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+}
+
+/// A k-way merge over sorted runs.
+pub struct Merge {
+    readers: Vec<RunReader>,
+    pending: Vec<Topics>,
+    heads: BinaryHeap<Reverse<(Bigram, usize)>>,
+
+    sample_size: Option<usize>,
+    rng: StdRng,
+}
+
+impl Merge {
+    fn advance(&mut self, run: usize) -> Result<()> {
+        if let Some((state, topics)) = self.readers[run].next_state()? {
+            self.pending[run] = topics;
+            self.heads.push(Reverse((state, run)));
+        }
+
+        Ok(())
+    }
+
+    fn next_state(&mut self) -> Result<Option<(Bigram, TopicMap, ObservationCounts)>> {
+        let (state, run) = match self.heads.pop() {
+            Some(Reverse(head)) => head,
+            None => return Ok(None),
+        };
+
+        let mut topics = hashbrown::HashMap::<Bigram, (usize, Vec<Observation>)>::new();
+        let mut run = run;
+
+        loop {
+            for (topic, seen, observations) in self.pending[run].drain(..) {
+                let (total, merged) = topics.entry(topic).or_insert((0, Vec::new()));
+
+                match self.sample_size {
+                    Some(capacity) => {
+                        *merged = sample::merge_samples(
+                            mem::take(merged),
+                            *total,
+                            observations,
+                            seen,
+                            capacity,
+                            &mut self.rng,
+                        )
+                    }
+                    None => merged.extend(observations),
+                }
+
+                *total += seen;
+            }
+
+            self.advance(run)?;
+
+            run = match self.heads.peek() {
+                Some(Reverse((next, _))) if *next == state => self.heads.pop().unwrap().0 .1,
+                _ => break,
+            };
+        }
+
+        let mut topic_map = TopicMap::with_capacity(topics.len());
+        let mut counts = ObservationCounts::new();
+
+        for (topic, (seen, observations)) in topics {
+            if seen > observations.len() {
+                counts.insert(topic.clone(), seen);
+            }
+
+            topic_map.insert(topic, observations);
+        }
+
+        Ok(Some((state, topic_map, counts)))
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<(Bigram, TopicMap, ObservationCounts)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_state().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::SeedableRng;
+
+    fn write_run(runs: &mut Runs, states: &[(&str, &str, i32, Option<&str>)]) {
+        let mut run = runs.create().unwrap();
+        for &(s, t, seq_num, successor) in states {
+            run.write_state((s, s), 1).unwrap();
+            run.write_topic((t, t), 1, 1).unwrap();
+            run.write_observation(seq_num, successor).unwrap();
+        }
+
+        run.finish().unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = std::env::temp_dir().join(format!("nessie-test-{}", std::process::id()));
+        let mut runs = Runs::new(&dir).unwrap();
+
+        write_run(&mut runs, &[("a", "x", 0, Some("b")), ("c", "x", 1, None)]);
+        write_run(&mut runs, &[("a", "y", 2, None), ("b", "x", 3, Some("c"))]);
+
+        let merged: Vec<_> = runs
+            .merge(None, StdRng::seed_from_u64(0))
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        let states: Vec<_> = merged.iter().map(|(s, _, _)| s.0.as_str()).collect();
+        assert_eq!(states, vec!["a", "b", "c"]);
+
+        let topic_map = &merged[0].1;
+        assert_eq!(topic_map.len(), 2);
+        assert_eq!(
+            topic_map[&("x".to_string(), "x".to_string())],
+            vec![(0, Some("b".to_string()))]
+        );
+        assert_eq!(
+            topic_map[&("y".to_string(), "y".to_string())],
+            vec![(2, None)]
+        );
+
+        drop(runs);
+        let _ = fs::remove_dir(dir);
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = std::env::temp_dir().join(format!("nessie-test-compact-{}", std::process::id()));
+        let mut runs = Runs::new(&dir).unwrap();
+
+        write_run(&mut runs, &[("a", "x", 0, Some("b")), ("c", "x", 1, None)]);
+        write_run(&mut runs, &[("a", "x", 2, None), ("b", "x", 3, Some("c"))]);
+
+        let mut states = Vec::new();
+        runs.compact(None, StdRng::seed_from_u64(0), |state, _, _| {
+            states.push(state.0.clone())
+        })
+        .unwrap();
+
+        assert_eq!(states, vec!["a", "b", "c"]);
+        assert_eq!(runs.len(), 1);
+
+        let merged: Vec<_> = runs
+            .merge(None, StdRng::seed_from_u64(0))
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0].1[&("x".to_string(), "x".to_string())].len(), 2);
+
+        drop(runs);
+        let _ = fs::remove_dir(dir);
+    }
+}
@@ -0,0 +1,665 @@
+use crate::approx::SpaceSaving;
+use crate::error::{Error, Result};
+use crate::rules::{Rules, WithRules, DEFAULT_RULES};
+
+use deunicode::deunicode;
+use hashbrown::HashSet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use std::{fmt, path::Path, str::FromStr};
+
+/// Turns raw lines into the words a chain is trained on.
+///
+/// A line is normalised into an owned string first, which the words then
+/// borrow from. Words may also borrow from the tokenizer, for those it
+/// rewrites.
+pub trait Tokenizer {
+    fn normalize(&self, line: &str) -> String;
+
+    /// Splits a normalised line into words.
+    fn split<'a>(&'a self, line: &'a str) -> Vec<&'a str>;
+
+    /// Whether `word` stays in the token stream.
+    fn filter(&self, _word: &str) -> bool {
+        true
+    }
+
+    /// Splits and filters a normalised line.
+    fn tokenize<'a>(&'a self, line: &'a str) -> Vec<&'a str> {
+        let mut words = self.split(line);
+        words.retain(|word| self.filter(word));
+
+        words
+    }
+
+    /// Joins generated words back into text.
+    fn detokenize(&self, words: &[&str]) -> String {
+        words.join(" ")
+    }
+}
+
+/// The original tokenizer: transliterates to ASCII, strips everything but
+/// word characters and whitespace, lowercases and splits on whitespace.
+/// `TokenizerKind::Default` builds it with `DEFAULT_RULES`.
+///
+/// Built with `default`. The constructors taking stop words and the
+/// inherent `sanitize` and `split` are the API from before `Tokenizer` and
+/// `StopWords`, kept for existing callers.
+pub struct LineProcessor {
+    special_chars_re: Regex,
+    // Dropped from the token stream, and applied by `sanitize`, only set by
+    // the deprecated constructors.
+    stop_words: HashSet<String>,
+    rules: Option<Rules>,
+}
+
+impl LineProcessor {
+    /// Drops the whitespace-separated `stop_words` from the token stream.
+    #[deprecated(note = "use `LineProcessor::default()` with `StopWords`")]
+    pub fn new(stop_words: &str) -> Self {
+        let special_chars_re = Regex::new(r"[^\w\s]").unwrap();
+        let stop_words = special_chars_re
+            .replace_all(stop_words, "")
+            .split_ascii_whitespace()
+            .map(String::from)
+            .collect();
+
+        LineProcessor {
+            special_chars_re,
+            stop_words,
+            rules: Some(Rules::new(DEFAULT_RULES).unwrap()),
+        }
+    }
+
+    #[deprecated(note = "use `LineProcessor::default()` with `StopWords::from_file`")]
+    #[allow(deprecated)]
+    pub fn from_stop_words_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(&std::fs::read_to_string(path)?))
+    }
+
+    /// Normalizes `line` with the default rules, as before they were
+    /// rules.
+    #[deprecated(note = "use `Tokenizer::normalize` on `TokenizerKind::Default.build()`")]
+    pub fn sanitize(&self, line: &str) -> String {
+        match &self.rules {
+            Some(rules) => self.normalize(&rules.rewrite_line(line.to_string())),
+            None => self.normalize(line),
+        }
+    }
+
+    /// Splits a sanitized line into words, dropping the stop words given to
+    /// `new`.
+    #[deprecated(note = "use `Tokenizer::tokenize`")]
+    pub fn split<'b>(&self, line: &'b str) -> Vec<&'b str> {
+        line.split_ascii_whitespace()
+            .filter(|word| self.filter(word))
+            .collect()
+    }
+}
+
+impl Default for LineProcessor {
+    fn default() -> Self {
+        LineProcessor {
+            special_chars_re: Regex::new(r"[^\w\s]").unwrap(),
+            stop_words: HashSet::new(),
+            rules: None,
+        }
+    }
+}
+
+impl Tokenizer for LineProcessor {
+    fn normalize(&self, line: &str) -> String {
+        let mut line = deunicode(line);
+
+        line = self.special_chars_re.replace_all(&line, "").to_string();
+        line.make_ascii_lowercase();
+
+        line
+    }
+
+    fn split<'a>(&'a self, line: &'a str) -> Vec<&'a str> {
+        line.split_ascii_whitespace().collect()
+    }
+
+    fn filter(&self, word: &str) -> bool {
+        !self.stop_words.contains(word)
+    }
+}
+
+/// Transliterates to ASCII and lowercases, but keeps punctuation attached to
+/// the words it is written against.
+#[derive(Default)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn normalize(&self, line: &str) -> String {
+        let mut line = deunicode(line);
+        line.make_ascii_lowercase();
+
+        line
+    }
+
+    fn split<'a>(&'a self, line: &'a str) -> Vec<&'a str> {
+        line.split_ascii_whitespace().collect()
+    }
+}
+
+/// Keeps sentence punctuation as tokens of its own, and apostrophes and
+/// hyphens within words, so that contractions such as "don't" and
+/// possessives such as "cat's" stay whole. Other symbols are dropped.
+///
+/// Detokenizing attaches punctuation to the word before it and capitalises
+/// the start of each sentence.
+pub struct PunctuationTokenizer {
+    token_re: Regex,
+}
+
+impl PunctuationTokenizer {
+    pub fn new() -> Self {
+        PunctuationTokenizer {
+            token_re: Regex::new(r"\w+(?:['-]\w+)*|[.!?]+|[,;:]").unwrap(),
+        }
+    }
+}
+
+impl Default for PunctuationTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tokenizer for PunctuationTokenizer {
+    fn normalize(&self, line: &str) -> String {
+        let mut line = deunicode(line);
+        line.make_ascii_lowercase();
+
+        line
+    }
+
+    fn split<'a>(&'a self, line: &'a str) -> Vec<&'a str> {
+        self.token_re.find_iter(line).map(|m| m.as_str()).collect()
+    }
+
+    fn detokenize(&self, words: &[&str]) -> String {
+        let mut text = String::new();
+        let mut sentence_start = true;
+
+        for &word in words {
+            let is_punctuation = !word.starts_with(|c: char| c.is_alphanumeric());
+
+            if !text.is_empty() && !is_punctuation {
+                text.push(' ');
+            }
+
+            match word {
+                _ if sentence_start || word == "i" || word.starts_with("i'") => {
+                    let mut chars = word.chars();
+                    text.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+                    text.push_str(chars.as_str());
+                }
+                _ => text.push_str(word),
+            }
+
+            sentence_start = match word.chars().last() {
+                Some('.') | Some('!') | Some('?') => true,
+                _ => sentence_start && is_punctuation,
+            };
+        }
+
+        text
+    }
+}
+
+/// Unicode normalization forms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical composition, which only merges equivalent encodings.
+    Nfc,
+    /// Compatibility composition, which also folds ligatures, full-width
+    /// forms and the like into their plain equivalents.
+    Nfkc,
+}
+
+/// Keeps text in its own script rather than transliterating it: normalizes
+/// it, applies full Unicode case folding and splits it on Unicode word
+/// boundaries, dropping punctuation.
+pub struct UnicodeTokenizer {
+    normalization: Normalization,
+}
+
+impl UnicodeTokenizer {
+    pub fn new(normalization: Normalization) -> Self {
+        UnicodeTokenizer { normalization }
+    }
+
+    fn compose(&self, text: &str) -> String {
+        match self.normalization {
+            Normalization::Nfc => text.nfc().collect(),
+            Normalization::Nfkc => text.nfkc().collect(),
+        }
+    }
+}
+
+impl Tokenizer for UnicodeTokenizer {
+    // Case folding can leave text unnormalized, so it is composed again.
+    fn normalize(&self, line: &str) -> String {
+        self.compose(&caseless::default_case_fold_str(&self.compose(line)))
+    }
+
+    fn split<'a>(&'a self, line: &'a str) -> Vec<&'a str> {
+        line.unicode_words().collect()
+    }
+}
+
+/// The built-in tokenizers, by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenizerKind {
+    Default,
+    Whitespace,
+    Punctuation,
+    Unicode(Normalization),
+}
+
+impl TokenizerKind {
+    /// The tokenizer with its default rules, if it has any.
+    pub fn build(&self) -> Box<dyn Tokenizer> {
+        self.build_with(self.default_rules())
+    }
+
+    /// The tokenizer with `rules` in place of its default ones.
+    pub fn build_with(&self, rules: Option<Rules>) -> Box<dyn Tokenizer> {
+        let tokenizer: Box<dyn Tokenizer> = match *self {
+            TokenizerKind::Default => Box::new(LineProcessor::default()),
+            TokenizerKind::Whitespace => Box::new(WhitespaceTokenizer),
+            TokenizerKind::Punctuation => Box::new(PunctuationTokenizer::new()),
+            TokenizerKind::Unicode(normalization) => Box::new(UnicodeTokenizer::new(normalization)),
+        };
+
+        match rules {
+            Some(rules) => Box::new(WithRules::new(tokenizer, rules)),
+            None => tokenizer,
+        }
+    }
+
+    /// The rules applied when no rules file replaces them: `DEFAULT_RULES`
+    /// for the default tokenizer, and none for the others.
+    pub fn default_rules(&self) -> Option<Rules> {
+        match self {
+            TokenizerKind::Default => Some(Rules::new(DEFAULT_RULES).unwrap()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TokenizerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(TokenizerKind::Default),
+            "whitespace" => Ok(TokenizerKind::Whitespace),
+            "punctuation" => Ok(TokenizerKind::Punctuation),
+            "unicode" | "unicode-nfkc" => Ok(TokenizerKind::Unicode(Normalization::Nfkc)),
+            "unicode-nfc" => Ok(TokenizerKind::Unicode(Normalization::Nfc)),
+            _ => Err(Error::InvalidTokenizer(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for TokenizerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenizerKind::Default => write!(f, "default"),
+            TokenizerKind::Whitespace => write!(f, "whitespace"),
+            TokenizerKind::Punctuation => write!(f, "punctuation"),
+            TokenizerKind::Unicode(Normalization::Nfkc) => write!(f, "unicode-nfkc"),
+            TokenizerKind::Unicode(Normalization::Nfc) => write!(f, "unicode-nfc"),
+        }
+    }
+}
+
+/// Where stop words are left out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StopWordMode {
+    /// Only from topic selection. The chain keeps them, so generated text
+    /// keeps its function words.
+    Topics,
+    /// From the token stream altogether.
+    Everywhere,
+}
+
+/// The built-in stop word lists, by language.
+pub const STOP_WORD_LANGUAGES: &[(&str, &str)] = &[
+    ("dutch", include_str!("stop_words/dutch.txt")),
+    ("english", include_str!("stop_words/english.txt")),
+    ("french", include_str!("stop_words/french.txt")),
+    ("german", include_str!("stop_words/german.txt")),
+    ("italian", include_str!("stop_words/italian.txt")),
+    ("portuguese", include_str!("stop_words/portuguese.txt")),
+    ("spanish", include_str!("stop_words/spanish.txt")),
+];
+
+/// Words left out of topics, and optionally out of the chain. The same
+/// value filters the token stream, with `filter`, and is given to the chain,
+/// which leaves the words out of topics and records the mode in the model.
+#[derive(Clone, Debug)]
+pub struct StopWords {
+    words: HashSet<String>,
+    mode: StopWordMode,
+}
+
+impl Default for StopWords {
+    fn default() -> Self {
+        StopWords {
+            words: HashSet::new(),
+            mode: StopWordMode::Topics,
+        }
+    }
+}
+
+impl StopWords {
+    /// Reads whitespace-separated stop words, tokenized the same way as the
+    /// lines they will be matched against. Lines starting with `#` are
+    /// comments.
+    pub fn new(stop_words: &str, tokenizer: &dyn Tokenizer, mode: StopWordMode) -> Self {
+        let mut new = StopWords {
+            words: HashSet::new(),
+            mode,
+        };
+
+        new.add(stop_words, tokenizer);
+        new
+    }
+
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        tokenizer: &dyn Tokenizer,
+        mode: StopWordMode,
+    ) -> Result<Self> {
+        Ok(Self::new(&std::fs::read_to_string(path)?, tokenizer, mode))
+    }
+
+    /// Adds stop words in the same format as `new`.
+    pub fn add(&mut self, stop_words: &str, tokenizer: &dyn Tokenizer) {
+        for line in stop_words.lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            let line = tokenizer.normalize(line);
+            self.words
+                .extend(tokenizer.tokenize(&line).into_iter().map(String::from));
+        }
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P, tokenizer: &dyn Tokenizer) -> Result<()> {
+        self.add(&std::fs::read_to_string(path)?, tokenizer);
+        Ok(())
+    }
+
+    /// Adds the built-in list for `language`.
+    pub fn add_language(&mut self, language: &str, tokenizer: &dyn Tokenizer) -> Result<()> {
+        match STOP_WORD_LANGUAGES
+            .iter()
+            .find(|(name, _)| *name == language)
+        {
+            Some((_, stop_words)) => {
+                self.add(stop_words, tokenizer);
+                Ok(())
+            }
+            None => {
+                let languages: Vec<_> = STOP_WORD_LANGUAGES.iter().map(|(name, _)| *name).collect();
+                Err(Error::InvalidStopWords(format!(
+                    "no built-in list for '{}', try one of {}",
+                    language,
+                    languages.join(", ")
+                )))
+            }
+        }
+    }
+
+    /// Adds already tokenized words, such as those from `WordCounts::top`.
+    pub fn extend<I: IntoIterator<Item = String>>(&mut self, words: I) {
+        self.words.extend(words);
+    }
+
+    pub fn words(&self) -> &HashSet<String> {
+        &self.words
+    }
+
+    /// The words in order, as stored in model metadata.
+    pub fn sorted(&self) -> Vec<String> {
+        let mut words: Vec<_> = self.words.iter().cloned().collect();
+        words.sort_unstable();
+        words
+    }
+
+    pub fn mode(&self) -> StopWordMode {
+        self.mode
+    }
+
+    /// Drops stop words from `words` if they are left out everywhere.
+    pub fn filter(&self, words: &mut Vec<&str>) {
+        if self.mode == StopWordMode::Everywhere {
+            words.retain(|word| !self.words.contains(*word));
+        }
+    }
+}
+
+/// How `WordCounts` ranks words.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WordRanking {
+    /// By number of occurrences.
+    Frequency,
+    /// By number of documents they occur in.
+    Documents,
+}
+
+impl FromStr for WordRanking {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "frequency" => Ok(WordRanking::Frequency),
+            "documents" => Ok(WordRanking::Documents),
+            _ => Err(Error::InvalidStopWords(format!(
+                "unknown ranking '{}'",
+                name
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for WordRanking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WordRanking::Frequency => write!(f, "frequency"),
+            WordRanking::Documents => write!(f, "documents"),
+        }
+    }
+}
+
+/// Approximate counts of the most common words, for deriving stop words.
+/// Only `capacity` words are tracked, with Space-Saving, so memory stays
+/// fixed however many distinct words the input has.
+pub struct WordCounts {
+    counts: SpaceSaving<String>,
+    ranking: WordRanking,
+}
+
+impl WordCounts {
+    /// Panics if `capacity` is 0.
+    pub fn new(capacity: usize, ranking: WordRanking) -> Self {
+        WordCounts {
+            counts: SpaceSaving::new(capacity),
+            ranking,
+        }
+    }
+
+    /// Counts the words of one document.
+    pub fn observe(&mut self, words: &[&str]) {
+        let mut seen = HashSet::new();
+
+        for &word in words {
+            if self.ranking == WordRanking::Documents && !seen.insert(word) {
+                continue;
+            }
+
+            self.counts.add(word, |w| w == word, || word.to_string());
+        }
+    }
+
+    /// The `n` highest ranked words, ties broken alphabetically.
+    pub fn top(&self, n: usize) -> Vec<String> {
+        let mut words: Vec<_> = self.counts.iter().collect();
+
+        words.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+        words
+            .into_iter()
+            .take(n)
+            .map(|counted| counted.key.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_processor() {
+        let tokenizer = TokenizerKind::Default.build();
+        let line = tokenizer.normalize("Café, the 5 th day!");
+
+        assert_eq!(
+            tokenizer.tokenize(&line),
+            vec!["cafe", "the", "5", "nth", "day"]
+        );
+
+        // Without the default rules.
+        let tokenizer = TokenizerKind::Default.build_with(None);
+        assert_eq!(tokenizer.normalize("the 5 Th day"), "the 5 th day");
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_line_processor_stop_words() {
+        let processor = LineProcessor::new("the, a");
+        let line = processor.sanitize("Café, the 5 th day!");
+
+        assert_eq!(processor.split(&line), vec!["cafe", "5", "nth", "day"]);
+        assert_eq!(processor.tokenize(&line), processor.split(&line));
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let tokenizer = "whitespace".parse::<TokenizerKind>().unwrap().build();
+        let line = tokenizer.normalize("Don't stop, Café.");
+
+        assert_eq!(tokenizer.tokenize(&line), vec!["don't", "stop,", "cafe."]);
+    }
+
+    #[test]
+    fn test_punctuation() {
+        let tokenizer = PunctuationTokenizer::new();
+        let line = tokenizer.normalize("“Don't,” said the cat's well-fed owner... (Really?!)");
+        let words = tokenizer.tokenize(&line);
+
+        assert_eq!(
+            words,
+            vec!["don't", ",", "said", "the", "cat's", "well-fed", "owner", "...", "really", "?!"]
+        );
+
+        assert_eq!(
+            tokenizer.detokenize(&words),
+            "Don't, said the cat's well-fed owner... Really?!"
+        );
+
+        let words = ["i", "think", ".", "so", ",", "i'm", "here"];
+        assert_eq!(tokenizer.detokenize(&words), "I think. So, I'm here");
+    }
+
+    #[test]
+    fn test_unicode() {
+        let tokenizer = UnicodeTokenizer::new(Normalization::Nfkc);
+
+        // A decomposed "ü", a ligature, full-width letters and an "ß" that
+        // folds to "ss".
+        let line =
+            tokenizer.normalize("Gru\u{308}sse aus der ﬁrma ＡＢＣ, STRASSE Straße 東京都。");
+
+        assert_eq!(
+            tokenizer.tokenize(&line),
+            vec![
+                "grüsse", "aus", "der", "firma", "abc", "strasse", "strasse", "東", "京", "都"
+            ]
+        );
+
+        let tokenizer = UnicodeTokenizer::new(Normalization::Nfc);
+        let line = tokenizer.normalize("ＡＢＣ Ǆ");
+
+        assert_eq!(tokenizer.tokenize(&line), vec!["ａｂｃ", "ǆ"]);
+    }
+
+    #[test]
+    fn test_unicode_stop_words() {
+        let tokenizer = UnicodeTokenizer::new(Normalization::Nfkc);
+        let stop_words = StopWords::new("Über\nDIE", &tokenizer, StopWordMode::Everywhere);
+
+        let line = tokenizer.normalize("Die Brücke über die Straße");
+        let mut words = tokenizer.tokenize(&line);
+
+        stop_words.filter(&mut words);
+        assert_eq!(words, vec!["brücke", "strasse"]);
+    }
+
+    #[test]
+    fn test_stop_word_lists() {
+        let tokenizer = LineProcessor::default();
+        let mut stop_words = StopWords::new("# The\nof and\n", &tokenizer, StopWordMode::Topics);
+
+        assert!(stop_words.add_language("klingon", &tokenizer).is_err());
+        stop_words.add_language("german", &tokenizer).unwrap();
+
+        let words = stop_words.words();
+        assert!(words.contains("of") && words.contains("and") && !words.contains("the"));
+        assert!(words.contains("fur") && words.contains("uber"));
+    }
+
+    #[test]
+    fn test_word_counts() {
+        let count = |capacity, ranking| {
+            let mut counts = WordCounts::new(capacity, ranking);
+            counts.observe(&["the", "cat", "the", "mat", "the"]);
+            counts.observe(&["a", "cat", "a", "dog", "a", "a"]);
+            counts.top(2)
+        };
+
+        assert_eq!(count(16, WordRanking::Frequency), vec!["a", "the"]);
+        assert_eq!(count(16, WordRanking::Documents), vec!["cat", "a"]);
+
+        // Rare words are evicted, the common ones stay.
+        assert_eq!(count(4, WordRanking::Frequency), vec!["a", "the"]);
+    }
+
+    #[test]
+    fn test_stop_word_mode() {
+        let tokenizer = LineProcessor::default();
+        let line = tokenizer.normalize("The end of the line.");
+
+        let mut words = tokenizer.tokenize(&line);
+        let stop_words = StopWords::new("The\nof", &tokenizer, StopWordMode::Topics);
+
+        stop_words.filter(&mut words);
+        assert_eq!(words, vec!["the", "end", "of", "the", "line"]);
+
+        let stop_words = StopWords::new("The\nof", &tokenizer, StopWordMode::Everywhere);
+
+        stop_words.filter(&mut words);
+        assert_eq!(words, vec!["end", "line"]);
+    }
+}
@@ -2,17 +2,23 @@ use crate::clone_in::CloneIn;
 
 use std::{
     alloc::{Allocator, Layout},
+    convert::TryFrom,
     fmt::{Debug, Display, Error, Formatter},
     hash::{Hash, Hasher},
     marker::PhantomData,
     mem::{size_of, transmute, zeroed, MaybeUninit},
-    ptr::copy_nonoverlapping,
+    ptr::{copy_nonoverlapping, read_unaligned},
     slice::{from_raw_parts, from_raw_parts_mut},
     str::{from_utf8_unchecked, from_utf8_unchecked_mut},
 };
 
 const INLINE_CAP: usize = 15;
 
+// A boxed unigram keeps its pointer at the start of `data` and its full
+// length as a u32 after it. The marker only has room for 7 bits of length,
+// which it keeps too so that most unequal unigrams differ in the marker.
+const BOXED_LEN_OFFSET: usize = size_of::<usize>();
+
 const MARKER_LEN_MASK: u8 = 0b0111_1111;
 const MARKER_DISC_MASK: u8 = !MARKER_LEN_MASK;
 
@@ -37,9 +43,10 @@ impl Marker {
     }
 
     fn new_boxed(len: usize) -> Self {
-        Self((len as u8) | MARKER_DISC_MASK)
+        Self((len as u8 & MARKER_LEN_MASK) | MARKER_DISC_MASK)
     }
 
+    // Only the full length for inline unigrams.
     fn len(&self) -> usize {
         (self.0 & MARKER_LEN_MASK) as usize
     }
@@ -58,20 +65,38 @@ impl<A: Allocator> Unigram<A> {
         &mut *self.raw.as_mut_ptr()
     }
 
+    // `Repr` is packed, so the whole unigram may not be aligned for a u128.
+    unsafe fn raw_u128(&self) -> u128 {
+        read_unaligned(self.raw.as_ptr() as *const u128)
+    }
+
     pub fn len(&self) -> usize {
-        unsafe { self.inner().marker.len() }
+        unsafe {
+            match self.is_inline() {
+                true => self.inner().marker.len(),
+                false => {
+                    let len = self.inner().data.as_ptr().add(BOXED_LEN_OFFSET) as *const u32;
+                    len.read_unaligned() as usize
+                }
+            }
+        }
     }
 
     pub fn is_inline(&self) -> bool {
         unsafe { self.inner().marker.is_inline() }
     }
 
+    /// Bytes allocated outside of the unigram itself.
+    pub fn heap_bytes(&self) -> usize {
+        heap_bytes(self.len())
+    }
+
     unsafe fn data_ptr(&self) -> *const u8 {
         match self.is_inline() {
             true => &self.inner().data as *const u8,
             false => {
                 let ptr: *const *const u8 = transmute(&self.inner().data);
-                *ptr
+                read_unaligned(ptr)
             }
         }
     }
@@ -81,7 +106,7 @@ impl<A: Allocator> Unigram<A> {
             true => &mut self.inner_mut().data as *mut u8,
             false => {
                 let ptr: *mut *mut u8 = transmute(&self.inner_mut().data);
-                *ptr
+                read_unaligned(ptr)
             }
         }
     }
@@ -95,17 +120,23 @@ impl<A: Allocator> Unigram<A> {
     }
 
     unsafe fn from_slice_in_boxed(slice: &str, alloc: A) -> Self {
+        let len = u32::try_from(slice.len()).expect("unigram longer than 4 GiB");
         let mut out = Self {
             raw: MaybeUninit::uninit(),
         };
 
-        let layout = Layout::from_size_align_unchecked(slice.len(), 16);
-        let data = alloc.allocate(layout.pad_to_align()).unwrap().as_mut_ptr();
+        let data = alloc
+            .allocate(boxed_layout(slice.len()))
+            .unwrap()
+            .as_mut_ptr();
 
         copy_nonoverlapping(slice.as_ptr(), data, slice.len());
 
         let out_data_ptr: *mut *mut u8 = transmute(&out.inner_mut().data);
-        out_data_ptr.write(data);
+        out_data_ptr.write_unaligned(data);
+
+        let out_len_ptr = out.inner_mut().data.as_mut_ptr().add(BOXED_LEN_OFFSET) as *mut u32;
+        out_len_ptr.write_unaligned(len);
 
         out.inner_mut().marker = Marker::new_boxed(slice.len());
         out
@@ -152,7 +183,7 @@ impl<A: Allocator> CloneIn<A> for Unigram<A> {
                 let src = self.raw.as_ptr();
                 let dst = out.raw.as_mut_ptr();
 
-                copy_nonoverlapping(src, dst, size_of::<Self>());
+                copy_nonoverlapping(src, dst, 1);
             }
 
             out
@@ -178,16 +209,43 @@ impl<A: Allocator> AsMut<str> for Unigram<A> {
     }
 }
 
+/// Bytes a `Unigram` of `len` bytes allocates outside of itself.
+pub fn heap_bytes(len: usize) -> usize {
+    match len > INLINE_CAP {
+        true => boxed_layout(len).size(),
+        false => 0,
+    }
+}
+
+fn boxed_layout(len: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(len, 16).pad_to_align() }
+}
+
 impl<A: Allocator> Hash for Unigram<A> {
     fn hash<H: Hasher>(&self, hasher: &mut H) {
         if self.is_inline() {
-            hasher.write_u128(unsafe { *(self.raw.as_ptr() as *const u128) });
+            hasher.write_u128(unsafe { self.raw_u128() });
         } else {
             self.as_str().hash(hasher);
         }
     }
 }
 
+/// Hashes `slice` exactly as a `Unigram` holding it would be hashed, allowing
+/// lookups by `&str` without building (and possibly allocating) a `Unigram`.
+pub fn hash_str<H: Hasher>(slice: &str, hasher: &mut H) {
+    if slice.len() > INLINE_CAP {
+        slice.hash(hasher);
+        return;
+    }
+
+    let mut raw = [0u8; INLINE_CAP + 1];
+    raw[..slice.len()].copy_from_slice(slice.as_bytes());
+    raw[INLINE_CAP] = Marker::new_inline(slice.len()).0;
+
+    hasher.write_u128(u128::from_ne_bytes(raw));
+}
+
 impl<A: Allocator> Debug for Unigram<A> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         Debug::fmt(self.as_str(), f)
@@ -221,8 +279,8 @@ impl<A: Allocator> PartialEq<Unigram<A>> for Unigram<A> {
         }
 
         if self.is_inline() {
-            let v1 = unsafe { *(self.raw.as_ptr() as *const u128) };
-            let v2 = unsafe { *(other.raw.as_ptr() as *const u128) };
+            let v1 = unsafe { self.raw_u128() };
+            let v2 = unsafe { other.raw_u128() };
 
             return v1 == v2;
         }
@@ -283,6 +341,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_long() {
+        for len in [127, 128, 255, 256, 1000] {
+            let s = "ü".repeat(len);
+            let u = Unigram::from_slice_in(&s, Global::default());
+
+            assert_eq!(u.len(), s.len());
+            assert_eq!(u.as_str(), s);
+            assert!(u == Unigram::from_slice_in(&s, Global::default()));
+
+            let t = "ü".repeat(len + 64);
+            assert!(u != Unigram::from_slice_in(&t, Global::default()));
+        }
+    }
+
     #[test]
     fn test_inline() {
         for s in TEST_STRS {
@@ -331,6 +404,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_hash_str() {
+        use std::collections::hash_map::DefaultHasher;
+
+        for s in TEST_STRS {
+            let u = Unigram::from_slice_in(s, Global::default());
+
+            let mut h1 = DefaultHasher::new();
+            let mut h2 = DefaultHasher::new();
+
+            u.hash(&mut h1);
+            hash_str(s, &mut h2);
+
+            assert_eq!(h1.finish(), h2.finish());
+        }
+    }
+
     #[test]
     fn test_eq_str() {
         for s in TEST_STRS {